@@ -0,0 +1,174 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use thiserror::Error;
+
+use crate::{embedded::{SevenZip, SevenZipError}, md5::Md5Error};
+
+/// Extension of the sidecar listing the MD5 of every archive volume, in `md5sum` format.
+pub const CHECKSUM_EXTENSION: &str = "md5";
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Could not find archive {0}")]
+    NotFound(String),
+    #[error("{0} does not match its checksum")]
+    ChecksumMismatch(String),
+    #[error("Checksum file {0} is malformed")]
+    MalformedChecksums(String),
+    #[error(transparent)]
+    SevenZip(#[from] SevenZipError),
+    #[error(transparent)]
+    Md5(#[from] Md5Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    SevenZip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::SevenZip => "7z",
+        }
+    }
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().trim_start_matches('.').to_ascii_lowercase().as_str() {
+            "zip" => Ok(Self::Zip),
+            "7z" | "7zip" => Ok(Self::SevenZip),
+            _ => Err(format!("unknown archive format {:?}, expected zip or 7z", text)),
+        }
+    }
+}
+
+/// The archive a path names, with any `.001` style volume suffix dropped.
+pub fn base_path(path: &Path) -> PathBuf {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.len() == 3 && ext.bytes().all(|b| b.is_ascii_digit()) => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Every file making up the archive at `path`: the archive itself, or its numbered volumes when split.
+pub fn volumes(path: &Path) -> Vec<PathBuf> {
+    let base = base_path(path);
+    if base.is_file() {
+        return vec![base];
+    }
+    (1..)
+        .map(|index| volume_path(&base, index))
+        .take_while(|volume| volume.is_file())
+        .collect()
+}
+
+pub fn exists(path: &Path) -> bool {
+    !volumes(path).is_empty()
+}
+
+fn volume_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_owned();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = base_path(path).into_os_string();
+    name.push(format!(".{}", CHECKSUM_EXTENSION));
+    PathBuf::from(name)
+}
+
+/// Packs the contents of `source_dir` into `archive`, split into volumes of `volume_size` bytes
+/// if given, and writes the checksum sidecar. Top-level files named in `exclude` are left out.
+/// Returns the written volumes.
+pub fn create(
+    source_dir: &Path,
+    archive: &Path,
+    format: ArchiveFormat,
+    volume_size: Option<u64>,
+    exclude: &[&str],
+) -> Result<Vec<PathBuf>, ArchiveError> {
+    for stale in volumes(archive).into_iter().chain(std::iter::once(checksum_path(archive))) {
+        if stale.exists() {
+            fs::remove_file(stale)?;
+        }
+    }
+
+    SevenZip::instance()?.compress(source_dir, archive, format.extension(), volume_size, exclude)?;
+    let volumes = volumes(archive);
+    if volumes.is_empty() {
+        return Err(ArchiveError::NotFound(archive.display().to_string()));
+    }
+    write_checksums(archive, &volumes)?;
+    Ok(volumes)
+}
+
+fn write_checksums(archive: &Path, volumes: &[PathBuf]) -> Result<(), ArchiveError> {
+    let mut content = String::new();
+    for volume in volumes {
+        let md5 = crate::md5::calculate_md5(volume)?;
+        let name = volume.file_name().unwrap_or_default().to_string_lossy();
+        content.push_str(&format!("{}  {}\n", md5, name));
+    }
+    fs::write(checksum_path(archive), content)?;
+    Ok(())
+}
+
+/// Checks every volume against the checksum sidecar. Returns false when there is no sidecar.
+pub fn verify(path: &Path) -> Result<bool, ArchiveError> {
+    let sidecar = checksum_path(path);
+    if !sidecar.is_file() {
+        return Ok(false);
+    }
+    let dir = sidecar.parent().unwrap_or(Path::new("."));
+
+    let content = fs::read_to_string(&sidecar)?;
+    let mut listed = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (md5, name) = line
+            .split_once(char::is_whitespace)
+            .map(|(md5, name)| (md5.trim(), name.trim().trim_start_matches('*')))
+            .filter(|(_, name)| !name.is_empty() && !name.contains(['/', '\\']))
+            .ok_or_else(|| ArchiveError::MalformedChecksums(sidecar.display().to_string()))?;
+        let volume = dir.join(name);
+        if !volume.is_file() {
+            return Err(ArchiveError::NotFound(volume.display().to_string()));
+        }
+        if !crate::md5::calculate_md5(&volume)?.eq_ignore_ascii_case(md5) {
+            return Err(ArchiveError::ChecksumMismatch(volume.display().to_string()));
+        }
+        listed += 1;
+    }
+
+    if listed != volumes(path).len() {
+        return Err(ArchiveError::MalformedChecksums(sidecar.display().to_string()));
+    }
+    Ok(true)
+}
+
+/// Verifies the archive at `path` against its checksum sidecar, if it has one, and extracts it.
+/// `path` may name the archive, a split archive without its volume suffix, or its first volume.
+pub fn extract_verified(path: &Path, destination: &Path) -> Result<(), ArchiveError> {
+    let volumes = volumes(path);
+    let Some(first) = volumes.first() else {
+        return Err(ArchiveError::NotFound(path.display().to_string()));
+    };
+
+    println!("Verifying {}...", base_path(path).display());
+    if !verify(path)? {
+        println!("No {} checksum file found next to the archive, skipping verification", CHECKSUM_EXTENSION);
+    }
+    if volumes.len() > 1 {
+        println!("Extracting {} volumes...", volumes.len());
+    }
+    SevenZip::instance()?.extract_to(first, destination)?;
+    Ok(())
+}
@@ -1,5 +1,7 @@
 
-use std::{path::{Path, PathBuf}, sync::OnceLock};
+use std::{path::{Path, PathBuf}, str::FromStr, sync::OnceLock};
+
+use super::MemoryMode;
 
 #[derive(Debug, thiserror::Error)]
 pub enum PatchError {
@@ -10,7 +12,141 @@ pub enum PatchError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Failed to run patch command for file {0}")]
-    PatchCommandFailed(String)
+    PatchCommandFailed(String),
+    #[error("Invalid hdiffz option: {0}")]
+    InvalidOption(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compressor {
+    Zlib,
+    Ldef,
+    Bzip2,
+    Pbzip2,
+    Lzma,
+    Lzma2,
+    Zstd,
+}
+
+impl Compressor {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zlib => "zlib",
+            Self::Ldef => "ldef",
+            Self::Bzip2 => "bzip2",
+            Self::Pbzip2 => "pbzip2",
+            Self::Lzma => "lzma",
+            Self::Lzma2 => "lzma2",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for Compressor {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        [Self::Zlib, Self::Ldef, Self::Bzip2, Self::Pbzip2, Self::Lzma, Self::Lzma2, Self::Zstd]
+            .into_iter()
+            .find(|compressor| compressor.name() == text)
+            .ok_or_else(|| format!("unknown compressor {:?}", text))
+    }
+}
+
+/// hdiffz tuning; unset fields keep hdiffz's own defaults.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiffOptions {
+    /// `-m` or `-s`; memory mode gives the smallest patches but needs several times the old file in RAM.
+    pub memory_mode: Option<MemoryMode>,
+    /// `-s-N` in stream mode, `-block-N` in memory mode.
+    pub match_block_size: Option<u64>,
+    /// `-c-type`; patches are stored uncompressed when unset.
+    pub compressor: Option<Compressor>,
+    /// Level passed along with the compressor, ignored without one.
+    pub compress_level: Option<u32>,
+    /// `-p-N`.
+    pub threads: Option<u32>,
+}
+
+impl DiffOptions {
+    /// Reads `--hdiff-*` flags, ignoring every other argument.
+    pub fn from_args(args: &[String]) -> Result<Self, PatchError> {
+        let mut options = Self::default();
+        for arg in args.iter().filter_map(|arg| arg.strip_prefix("--hdiff-")) {
+            let (key, value) = arg.split_once('=').ok_or_else(|| PatchError::InvalidOption(format!("--hdiff-{} needs a value", arg)))?;
+            let invalid = |e: String| PatchError::InvalidOption(format!("--hdiff-{}: {}", key, e));
+            match key {
+                "mode" => options.memory_mode = Some(value.parse().map_err(invalid)?),
+                "match-block-size" => {
+                    options.match_block_size = Some(crate::utils::parse_size(value).ok_or_else(|| invalid(format!("invalid size {:?}", value)))?);
+                }
+                "compress" => {
+                    let (name, level) = match value.split_once('-') {
+                        Some((name, level)) => (name, Some(level.parse().map_err(|_| invalid(format!("invalid level {:?}", level)))?)),
+                        None => (value, None),
+                    };
+                    options.compressor = Some(name.parse().map_err(invalid)?);
+                    options.compress_level = level;
+                }
+                "threads" => options.threads = Some(value.parse().map_err(|_| invalid(format!("invalid thread count {:?}", value)))?),
+                _ => return Err(invalid("unknown option".to_string())),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Fills every unset field from `fallback`.
+    pub fn or(&self, fallback: &DiffOptions) -> DiffOptions {
+        DiffOptions {
+            memory_mode: self.memory_mode.or(fallback.memory_mode),
+            match_block_size: self.match_block_size.or(fallback.match_block_size),
+            compressor: self.compressor.or(fallback.compressor),
+            compress_level: if self.compressor.is_some() { self.compress_level } else { fallback.compress_level },
+            threads: self.threads.or(fallback.threads),
+        }
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match (self.memory_mode, self.match_block_size) {
+            (Some(MemoryMode::Stream), Some(size)) => args.push(format!("-s-{}", size)),
+            (Some(MemoryMode::Stream), None) => args.push("-s".to_string()),
+            (Some(MemoryMode::Memory), size) | (None, size @ Some(_)) => {
+                args.push("-m".to_string());
+                if let Some(size) = size {
+                    args.push(format!("-block-{}", size));
+                }
+            }
+            (None, None) => {}
+        }
+        if let Some(compressor) = self.compressor {
+            match self.compress_level {
+                Some(level) => args.push(format!("-c-{}-{}", compressor.name(), level)),
+                None => args.push(format!("-c-{}", compressor.name())),
+            }
+        }
+        if let Some(threads) = self.threads {
+            args.push(format!("-p-{}", threads));
+        }
+        args
+    }
+
+    /// Peak memory of one hdiffz run, following the requirements in hdiffz's usage text.
+    pub fn estimated_memory(&self, old_size: u64, new_size: u64) -> u64 {
+        match self.memory_mode {
+            Some(MemoryMode::Stream) => {
+                let block_size = self.match_block_size.unwrap_or(64).max(4);
+                let threads = self.threads.unwrap_or(4).max(1) as u64;
+                old_size.saturating_mul(16) / block_size + block_size * 5 * threads
+            }
+            _ => {
+                let factor = if old_size >= 2 << 30 { 9 } else { 5 };
+                old_size.saturating_mul(factor).saturating_add(new_size)
+            }
+        }
+    }
 }
 
 pub struct HDiff {
@@ -27,7 +163,13 @@ impl HDiff {
         })
     }
     pub fn diff(&self, old_path: &Path, new_path: &Path, out_patch: &Path) -> Result<(), PatchError> {
-       let args = [old_path.to_str().unwrap(), new_path.to_str().unwrap(), out_patch.to_str().unwrap()];
+        self.diff_with(old_path, new_path, out_patch, &DiffOptions::default())
+    }
+
+    pub fn diff_with(&self, old_path: &Path, new_path: &Path, out_patch: &Path, options: &DiffOptions) -> Result<(), PatchError> {
+       let option_args = options.args();
+       let mut args: Vec<&str> = option_args.iter().map(String::as_str).collect();
+       args.extend([old_path.to_str().unwrap(), new_path.to_str().unwrap(), out_patch.to_str().unwrap()]);
     
        let output = crate::utils::run_command_with_nixos_wrapper(&self.executable, &args)
            .map_err(|_| PatchError::PatchCommandFailed(out_patch.display().to_string()))?;
@@ -39,6 +181,29 @@ impl HDiff {
        Ok(())
     }
     
+    /// Diffs two directories into one patch; `ignore` holds paths relative to both roots,
+    /// directories ending in `/`.
+    pub fn diff_dir(&self, old_dir: &Path, new_dir: &Path, out_patch: &Path, ignore: &[String], options: &DiffOptions) -> Result<(), PatchError> {
+        let mut args = options.args();
+        if !ignore.is_empty() {
+            let escaped: Vec<String> = ignore.iter().map(|path| path.replace('#', "#:").replace('*', "*:")).collect();
+            args.push(format!("-g#{}", escaped.join("#")));
+        }
+        args.extend([old_dir.display().to_string(), new_dir.display().to_string(), out_patch.display().to_string()]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        let output = crate::utils::run_command_with_nixos_wrapper(&self.executable, &args)
+            .map_err(|_| PatchError::PatchCommandFailed(out_patch.display().to_string()))?;
+        if !output.status.success() {
+            return Err(PatchError::PatchCommandFailed(format!(
+                "{}: {}",
+                out_patch.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
     pub fn instance() -> Result<&'static Self, PatchError> {
         HDIFFZ_INSTANCE.get_or_try_init(Self::new)
     }
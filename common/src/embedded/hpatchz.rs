@@ -1,5 +1,7 @@
 
-use std::{path::PathBuf, sync::OnceLock};
+use std::{path::{Path, PathBuf}, sync::OnceLock};
+
+use super::MemoryMode;
 
 #[derive(Debug, thiserror::Error)]
 pub enum PatchError {
@@ -12,24 +14,81 @@ pub enum PatchError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Failed to run patch command for file {0}")]
-    PatchCommandFailed(String)
+    PatchCommandFailed(String),
+    #[error("Invalid hpatchz option: {0}")]
+    InvalidOption(String),
+}
+
+/// hpatchz tuning; unset fields keep hpatchz's own defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PatchOptions {
+    /// `-m` loads the old file into memory, `-s` streams it through the cache.
+    pub memory_mode: Option<MemoryMode>,
+    /// `-s-N`, only used in stream mode.
+    pub cache_size: Option<u64>,
+}
+
+impl PatchOptions {
+    /// Reads `--hpatchz-*` flags, ignoring every other argument.
+    pub fn from_args(args: &[String]) -> Result<Self, PatchError> {
+        let mut options = Self::default();
+        for arg in args.iter().filter_map(|arg| arg.strip_prefix("--hpatchz-")) {
+            let (key, value) = arg.split_once('=').ok_or_else(|| PatchError::InvalidOption(format!("--hpatchz-{} needs a value", arg)))?;
+            let invalid = |e: String| PatchError::InvalidOption(format!("--hpatchz-{}: {}", key, e));
+            match key {
+                "mode" => options.memory_mode = Some(value.parse().map_err(invalid)?),
+                "cache-size" => {
+                    options.cache_size = Some(crate::utils::parse_size(value).ok_or_else(|| invalid(format!("invalid size {:?}", value)))?);
+                }
+                _ => return Err(invalid("unknown option".to_string())),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        match (self.memory_mode, self.cache_size) {
+            (Some(MemoryMode::Memory), _) => vec!["-m".to_string()],
+            (_, Some(size)) => vec![format!("-s-{}", size)],
+            (Some(MemoryMode::Stream), None) => vec!["-s".to_string()],
+            (None, None) => Vec::new(),
+        }
+    }
 }
 
 pub struct HPatchz {
-    executable: PathBuf
+    executable: PathBuf,
+    options: PatchOptions,
 }
 
 static HPATCHZ_INSTANCE: OnceLock<HPatchz> = OnceLock::new();
+static HPATCHZ_OPTIONS: OnceLock<PatchOptions> = OnceLock::new();
 
 impl HPatchz {
     pub fn new() -> Result<Self, PatchError> {
         let executable = Self::extract_embedded_binary()?;
         Ok(Self {
-            executable
+            executable,
+            options: HPATCHZ_OPTIONS.get().cloned().unwrap_or_default(),
         })
     }
     
+    /// Sets the options the shared instance patches with; only takes effect before the first `instance()` call.
+    pub fn configure(options: PatchOptions) -> bool {
+        HPATCHZ_INSTANCE.get().is_none() && HPATCHZ_OPTIONS.set(options).is_ok()
+    }
+    
     pub fn patch(&self, source_file: &PathBuf, patch_file: &PathBuf, target_file: &PathBuf) -> Result<(), PatchError> {
+        self.patch_to(source_file, patch_file, target_file)?;
+        
+        self.remove_file(&patch_file);
+        if source_file != target_file && source_file.exists() {
+            self.remove_file(&source_file);
+        }
+        Ok(())
+    }
+    
+    pub fn patch_to(&self, source_file: &Path, patch_file: &Path, target_file: &Path) -> Result<(), PatchError> {
         if !patch_file.exists() {
             return Err(PatchError::NotFound(format!(
                 "Patch file not found: {}",
@@ -37,36 +96,55 @@ impl HPatchz {
             )));
         }
         
-        let output = crate::utils::run_command_with_nixos_wrapper(
-            &self.executable,
-            &[
-                &source_file.display().to_string(),
-                &patch_file.display().to_string(),
-                &target_file.display().to_string(),
-                "-f",
-            ],
-        );
+        let mut args = self.options.args();
+        args.extend([
+            source_file.display().to_string(),
+            patch_file.display().to_string(),
+            target_file.display().to_string(),
+            "-f".to_string(),
+        ]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = crate::utils::run_command_with_nixos_wrapper(&self.executable, &args);
 
-        
-        if let Ok(out) = output {
-            if out.status.success() {
-                self.remove_file(&patch_file);
-                if source_file != target_file && source_file.exists() {
-                    self.remove_file(&source_file);
-                }
-            } else {
-                return Err(PatchError::PatchCommandFailed(format!(
-                    "{}, exited with code {:?}",
-                    source_file.display(),
-                    out.status.code()
-                )));
-            }
-        } else {
-            return Err(PatchError::PatchCommandFailed(source_file.display().to_string()));
+        match output {
+            Ok(out) if out.status.success() => Ok(()),
+            Ok(out) => Err(PatchError::PatchCommandFailed(format!(
+                "{}, exited with code {:?}",
+                source_file.display(),
+                out.status.code()
+            ))),
+            Err(_) => Err(PatchError::PatchCommandFailed(source_file.display().to_string())),
         }
-        Ok(())
     }
     
+    /// Applies a directory patch, writing every file of the new directory into `out_dir`.
+    pub fn patch_dir(&self, old_dir: &Path, patch_file: &Path, out_dir: &Path) -> Result<(), PatchError> {
+        if !patch_file.exists() {
+            return Err(PatchError::NotFound(format!(
+                "Patch file not found: {}",
+                patch_file.display()
+            )));
+        }
+
+        let mut args = self.options.args();
+        args.extend([
+            old_dir.display().to_string(),
+            patch_file.display().to_string(),
+            out_dir.display().to_string(),
+            "-f".to_string(),
+        ]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match crate::utils::run_command_with_nixos_wrapper(&self.executable, &args) {
+            Ok(out) if out.status.success() => Ok(()),
+            Ok(out) => Err(PatchError::PatchCommandFailed(format!(
+                "{}, exited with code {:?}",
+                old_dir.display(),
+                out.status.code()
+            ))),
+            Err(_) => Err(PatchError::PatchCommandFailed(old_dir.display().to_string())),
+        }
+    }
+
     pub fn remove_file(&self, path: &PathBuf) {
         if let Err(e) = std::fs::remove_file(path) {
             eprintln!("Warning: failed to remove {}: {}", path.display(), e);
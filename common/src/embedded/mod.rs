@@ -5,4 +5,24 @@ pub mod sevenz;
 #[expect(ambiguous_glob_reexports)]
 pub use hdiffz::*;
 pub use hpatchz::*;
-pub use sevenz::*;
\ No newline at end of file
+pub use sevenz::*;
+
+/// Whether HDiffPatch loads the old file into memory or reads it as a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryMode {
+    Memory,
+    Stream,
+}
+
+impl std::str::FromStr for MemoryMode {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "memory" | "m" => Ok(Self::Memory),
+            "stream" | "s" => Ok(Self::Stream),
+            _ => Err(format!("unknown memory mode {:?}, expected memory or stream", text)),
+        }
+    }
+}
\ No newline at end of file
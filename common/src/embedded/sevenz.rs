@@ -13,6 +13,8 @@ pub enum SevenZipError {
     CommandError(#[source] std::io::Error),
     #[error("7-zip extraction failed: '{0}'")]
     ExtractionFailed(String),
+    #[error("7-zip compression failed: '{0}'")]
+    CompressionFailed(String),
     #[error("Embedded 7z.exe extraction failed: {0}")]
     EmbeddedExtractionFailed(String),
     #[error("IO error: {0}")]
@@ -54,6 +56,38 @@ impl SevenZip {
         Ok(())
     }
 
+    /// Adds the contents of `source_dir` to a new `archive_type` archive, as numbered volumes
+    /// of `volume_size` bytes when given. Top-level entries named in `exclude` are skipped.
+    pub fn compress(
+        &self,
+        source_dir: &Path,
+        archive: &Path,
+        archive_type: &str,
+        volume_size: Option<u64>,
+        exclude: &[&str],
+    ) -> Result<(), SevenZipError> {
+        let mut args = vec![
+            "a".to_string(),
+            format!("-t{}", archive_type),
+            archive.display().to_string(),
+            source_dir.join("*").display().to_string(),
+        ];
+        args.extend(exclude.iter().map(|name| format!("-x!{}", name)));
+        if let Some(volume_size) = volume_size {
+            args.push(format!("-v{}b", volume_size));
+        }
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = crate::utils::run_command_with_nixos_wrapper(&self.executable, &args)
+            .map_err(SevenZipError::CommandError)?;
+
+        if !output.status.success() {
+            let stderr_msg = String::from_utf8_lossy(&output.stderr);
+            return Err(SevenZipError::CompressionFailed(stderr_msg.to_string()));
+        }
+
+        Ok(())
+    }
 
     fn extract_embedded_binary() -> Result<PathBuf, SevenZipError> {
         const SEVENZ_BIN: &[u8] = include_bytes!("../../../bins/7z");
@@ -1,6 +1,11 @@
 #![feature(once_cell_try)]
 
+pub mod archive;
 pub mod embedded;
 pub mod input;
 pub mod md5;
+pub mod pkg_version;
+pub mod safe_path;
+pub mod signing;
 pub mod utils;
+pub mod version;
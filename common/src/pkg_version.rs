@@ -0,0 +1,69 @@
+use std::{fs, path::Path};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PkgVersionError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MD5 error: {0}")]
+    Md5(#[from] crate::md5::Md5Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PkgVersionEntry {
+    #[serde(rename = "remoteName")]
+    pub remote_name: String,
+    pub md5: String,
+    #[serde(rename = "fileSize")]
+    pub file_size: u64,
+}
+
+pub const PKG_VERSION_FILE: &str = "pkg_version";
+
+static EXCLUDED_DIRS: &[&str] = &["Persistent/", "SDKCaches/"];
+
+pub fn is_excluded(rel_path: &str) -> bool {
+    rel_path == PKG_VERSION_FILE || EXCLUDED_DIRS.iter().any(|dir| rel_path.contains(dir))
+}
+
+pub fn scan_directory(root: &Path) -> Result<Vec<PkgVersionEntry>, PkgVersionError> {
+    let files: Vec<_> = walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let rel_path = e.path().strip_prefix(root).ok()?.to_string_lossy().replace("\\", "/");
+            (!is_excluded(&rel_path)).then(|| (rel_path, e.into_path()))
+        })
+        .collect();
+
+    let pb = crate::utils::create_progress_bar(files.len());
+    let entries = files
+        .into_par_iter()
+        .map(|(remote_name, path)| {
+            let md5 = crate::md5::calculate_md5(&path)?;
+            let file_size = fs::metadata(&path)?.len();
+            pb.inc(1);
+            Ok(PkgVersionEntry { remote_name, md5, file_size })
+        })
+        .collect::<Result<Vec<_>, PkgVersionError>>()?;
+    pb.finish();
+
+    Ok(entries)
+}
+
+pub fn write_pkg_version(path: &Path, entries: &[PkgVersionEntry]) -> Result<(), PkgVersionError> {
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_by(|a, b| a.remote_name.cmp(&b.remote_name));
+
+    let mut content = String::new();
+    for entry in entries {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::path::{Component, Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PathError {
+    #[error("Path is empty")]
+    Empty,
+    #[error("Absolute paths are not allowed: {0}")]
+    Absolute(String),
+    #[error("Path traversal is not allowed: {0}")]
+    Traversal(String),
+    #[error("Path {0} escapes {1} through a symlink")]
+    SymlinkEscape(String, String),
+    #[error("Failed to resolve {0}: {1}")]
+    Resolve(String, #[source] std::io::Error),
+}
+
+pub fn sanitize(rel_path: &str) -> Result<PathBuf, PathError> {
+    let normalized = rel_path.trim().replace("\\", "/");
+    if normalized.is_empty() {
+        return Err(PathError::Empty);
+    }
+
+    let is_drive_path = normalized.len() >= 2
+        && normalized.as_bytes()[1] == b':'
+        && normalized.as_bytes()[0].is_ascii_alphabetic();
+    if normalized.starts_with('/') || is_drive_path {
+        return Err(PathError::Absolute(rel_path.to_string()));
+    }
+
+    let mut sanitized = PathBuf::new();
+    for component in Path::new(&normalized).components() {
+        match component {
+            Component::Normal(part) => sanitized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(PathError::Traversal(rel_path.to_string())),
+            Component::RootDir | Component::Prefix(_) => return Err(PathError::Absolute(rel_path.to_string())),
+        }
+    }
+
+    if sanitized.as_os_str().is_empty() {
+        return Err(PathError::Empty);
+    }
+    Ok(sanitized)
+}
+
+pub fn safe_join(root: &Path, rel_path: &str) -> Result<PathBuf, PathError> {
+    let joined = root.join(sanitize(rel_path)?);
+
+    let Some(existing) = joined.ancestors().find(|p| p.symlink_metadata().is_ok()) else {
+        return Ok(joined);
+    };
+    if existing == root || !existing.starts_with(root) {
+        return Ok(joined);
+    }
+
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| PathError::Resolve(root.display().to_string(), e))?;
+    let canonical_existing = existing
+        .canonicalize()
+        .map_err(|e| PathError::Resolve(existing.display().to_string(), e))?;
+
+    if !canonical_existing.starts_with(&canonical_root) {
+        return Err(PathError::SymlinkEscape(rel_path.to_string(), root.display().to_string()));
+    }
+    Ok(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_relative_paths() {
+        assert_eq!(sanitize("StarRail_Data/a.pck").unwrap(), Path::new("StarRail_Data").join("a.pck"));
+        assert_eq!(sanitize(" ./StarRail_Data//sub/./b.txt ").unwrap(), Path::new("StarRail_Data").join("sub").join("b.txt"));
+    }
+
+    #[test]
+    fn sanitize_treats_backslashes_as_separators() {
+        assert_eq!(sanitize("StarRail_Data\\sub\\b.txt").unwrap(), Path::new("StarRail_Data").join("sub").join("b.txt"));
+    }
+
+    #[test]
+    fn sanitize_rejects_traversal() {
+        for path in ["..", "../a", "a/..", "a/../../b", "a\\..\\..\\b", "./../a"] {
+            assert!(matches!(sanitize(path), Err(PathError::Traversal(_))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn sanitize_rejects_absolute_paths() {
+        for path in ["/etc/passwd", "\\Windows\\System32", "C:/Windows", "c:\\Windows", "C:relative", "\\\\server\\share\\a", "//server/share/a", "\\\\?\\C:\\a"] {
+            assert!(matches!(sanitize(path), Err(PathError::Absolute(_))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn sanitize_rejects_empty_paths() {
+        for path in ["", "   ", ".", "./", "./."] {
+            assert!(matches!(sanitize(path), Err(PathError::Empty)), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn safe_join_stays_under_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("sub")).unwrap();
+
+        assert_eq!(safe_join(root.path(), "sub/a.txt").unwrap(), root.path().join("sub").join("a.txt"));
+        assert_eq!(safe_join(root.path(), "missing/a.txt").unwrap(), root.path().join("missing").join("a.txt"));
+        assert!(matches!(safe_join(root.path(), "sub/../../a.txt"), Err(PathError::Traversal(_))));
+        assert!(matches!(safe_join(root.path(), "/tmp/a.txt"), Err(PathError::Absolute(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn safe_join_rejects_symlink_escape() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("inside")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("inside"), root.path().join("alias")).unwrap();
+
+        assert!(matches!(safe_join(root.path(), "escape/a.txt"), Err(PathError::SymlinkEscape(..))));
+        assert!(matches!(safe_join(root.path(), "escape"), Err(PathError::SymlinkEscape(..))));
+        assert_eq!(safe_join(root.path(), "alias/a.txt").unwrap(), root.path().join("alias").join("a.txt"));
+    }
+}
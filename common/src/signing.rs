@@ -0,0 +1,344 @@
+use std::{collections::HashSet, fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}};
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use thiserror::Error;
+
+pub const SIGNATURE_NAME: &str = "signature.json";
+/// Extension of the public key written next to a generated signing key.
+pub const PUBLIC_KEY_EXTENSION: &str = "pub";
+
+/// Control files that steer how a package is applied. An unpacked package sharing the game
+/// folder can only be checked for the files it lists, but none of these may be left unlisted.
+pub const CONTROL_FILES: &[&str] = &[
+    "package_info.json",
+    "hdiffmap.json",
+    "hdifffiles.txt",
+    "deletefiles.txt",
+    "fileops.json",
+    "dirdiff.json",
+    "dirdiff.hdiff",
+    "ldiff_manifest~",
+];
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("Invalid key in {0}")]
+    InvalidKey(String),
+    #[error("Package is not signed")]
+    Unsigned,
+    #[error("Package is signed by {0}, which is not a trusted key")]
+    UntrustedKey(String),
+    #[error("{0} is not covered by the signature")]
+    UnlistedFile(String),
+    #[error("{0} does not match its signed contents")]
+    FileMismatch(String),
+    #[error("Signature does not match the package contents")]
+    BadSignature,
+    #[error("Failed to read {0}: {1}")]
+    Read(String, #[source] io::Error),
+    #[error("Malformed {0}: {1}")]
+    Malformed(String, #[source] serde_json::Error),
+    #[error("Failed to generate a key: {0}")]
+    Random(String),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct PackageSignature {
+    public_key: String,
+    files: Vec<SignedFile>,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Writes a new signing key to `path` and its public key next to it, returning the public key.
+pub fn generate_key(path: &Path) -> Result<String, SigningError> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| SigningError::Random(e.to_string()))?;
+    let key = SigningKey::from_bytes(&seed);
+    let public_key = to_hex(key.verifying_key().as_bytes());
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Never replace an existing key, which may be the only copy
+    let public_path = public_key_path(path);
+    if public_path.exists() {
+        return Err(SigningError::Io(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", public_path.display()))));
+    }
+    create_new(path, true)?.write_all(format!("{}\n", to_hex(&seed)).as_bytes())?;
+    create_new(&public_path, false)?.write_all(format!("{}\n", public_key).as_bytes())?;
+    Ok(public_key)
+}
+
+/// Creates `path`, failing if it exists; `private` files are readable by their owner only.
+fn create_new(path: &Path, private: bool) -> io::Result<File> {
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options.open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+pub fn public_key_path(key_path: &Path) -> PathBuf {
+    let mut name = key_path.as_os_str().to_owned();
+    name.push(format!(".{}", PUBLIC_KEY_EXTENSION));
+    PathBuf::from(name)
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, SigningError> {
+    let text = fs::read_to_string(path).map_err(|e| SigningError::Read(path.display().to_string(), e))?;
+    let seed = from_hex::<32>(text.trim()).ok_or_else(|| SigningError::InvalidKey(path.display().to_string()))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Reads one hex public key per line; blank lines and `#` comments are ignored.
+pub fn load_trusted_keys(path: &Path) -> Result<Vec<VerifyingKey>, SigningError> {
+    let text = fs::read_to_string(path).map_err(|e| SigningError::Read(path.display().to_string(), e))?;
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| parse_public_key(line).ok_or_else(|| SigningError::InvalidKey(path.display().to_string())))
+        .collect()
+}
+
+fn parse_public_key(text: &str) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(&from_hex::<32>(text)?).ok()
+}
+
+/// Signs every file under `package_dir` except the top-level files named in `exclude`, and
+/// writes the signature next to them. Returns the number of files signed.
+pub fn sign_package(package_dir: &Path, key: &SigningKey, exclude: &[&str]) -> Result<usize, SigningError> {
+    let mut files = Vec::new();
+    for path in package_files(package_dir)? {
+        if exclude.contains(&path.as_str()) {
+            continue;
+        }
+        files.push(hash_file(package_dir, path)?);
+    }
+    let signature = PackageSignature {
+        public_key: to_hex(key.verifying_key().as_bytes()),
+        signature: to_hex(&key.sign(&digest_files(&files)).to_bytes()),
+        files,
+    };
+    let json_data = serde_json::to_string_pretty(&signature).map_err(io::Error::other)?;
+    fs::write(package_dir.join(SIGNATURE_NAME), json_data)?;
+    Ok(signature.files.len())
+}
+
+/// Checks the package signature against `trusted` keys and every signed file against its
+/// hash, returning the signing key in hex. With `exact`, `package_dir` holds nothing but the
+/// package and any file the signature does not list is refused; otherwise the package shares
+/// its folder with the game and only unlisted [`CONTROL_FILES`] are.
+pub fn verify_package(package_dir: &Path, trusted: &[VerifyingKey], exact: bool) -> Result<String, SigningError> {
+    let path = package_dir.join(SIGNATURE_NAME);
+    if !path.exists() {
+        return Err(SigningError::Unsigned);
+    }
+    let json_data = fs::read_to_string(&path).map_err(|e| SigningError::Read(path.display().to_string(), e))?;
+    let signed: PackageSignature = serde_json::from_str(&json_data).map_err(|e| SigningError::Malformed(SIGNATURE_NAME.to_string(), e))?;
+
+    let public_key = parse_public_key(&signed.public_key).ok_or_else(|| SigningError::InvalidKey(SIGNATURE_NAME.to_string()))?;
+    if !trusted.contains(&public_key) {
+        return Err(SigningError::UntrustedKey(signed.public_key));
+    }
+    let signature = from_hex::<64>(&signed.signature).map(|bytes| Signature::from_bytes(&bytes)).ok_or(SigningError::BadSignature)?;
+    public_key.verify(&digest_files(&signed.files), &signature).map_err(|_| SigningError::BadSignature)?;
+
+    let listed: HashSet<&str> = signed.files.iter().map(|file| file.path.as_str()).collect();
+    let unlisted = if exact {
+        package_files(package_dir)?.into_iter().find(|path| !listed.contains(path.as_str()))
+    } else {
+        CONTROL_FILES.iter().find(|name| package_dir.join(name).is_file() && !listed.contains(*name)).map(|name| name.to_string())
+    };
+    if let Some(path) = unlisted {
+        return Err(SigningError::UnlistedFile(path));
+    }
+
+    for file in &signed.files {
+        let actual = hash_file(package_dir, file.path.clone()).map_err(|_| SigningError::FileMismatch(file.path.clone()))?;
+        if actual.size != file.size || actual.sha256 != file.sha256 {
+            return Err(SigningError::FileMismatch(file.path.clone()));
+        }
+    }
+    Ok(signed.public_key)
+}
+
+/// Paths of every file under `package_dir` but the signature itself, `/`-separated and sorted.
+fn package_files(package_dir: &Path) -> Result<Vec<String>, SigningError> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(package_dir).min_depth(1) {
+        let entry = entry.map_err(io::Error::other)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(package_dir).map_err(io::Error::other)?.to_string_lossy().replace('\\', "/");
+        if rel_path != SIGNATURE_NAME {
+            files.push(rel_path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn hash_file(package_dir: &Path, path: String) -> Result<SignedFile, SigningError> {
+    let full_path = crate::safe_path::safe_join(package_dir, &path).map_err(|_| SigningError::FileMismatch(path.clone()))?;
+    let mut file = File::open(&full_path).map_err(|e| SigningError::Read(full_path.display().to_string(), e))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok(SignedFile { path, size, sha256: to_hex(&hasher.finalize()) })
+}
+
+/// SHA-512 over each file's path, size and hash, so the signature pins the whole listing.
+fn digest_files(files: &[SignedFile]) -> Vec<u8> {
+    let mut hasher = Sha512::new();
+    for file in files {
+        hasher.update(file.path.as_bytes());
+        hasher.update([0]);
+        hasher.update(file.size.to_le_bytes());
+        hasher.update(file.sha256.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_package(key: &SigningKey) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("StarRail_Data")).unwrap();
+        fs::write(dir.path().join("hdiffmap.json"), r#"{"diff_map":[]}"#).unwrap();
+        fs::write(dir.path().join("StarRail_Data/a.pck.hdiff"), b"patch").unwrap();
+        assert_eq!(sign_package(dir.path(), key, &[]).unwrap(), 2);
+        dir
+    }
+
+    #[test]
+    fn verifies_what_it_signed() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = signed_package(&key);
+
+        let public_key = to_hex(key.verifying_key().as_bytes());
+        assert_eq!(verify_package(dir.path(), &[key.verifying_key()], true).unwrap(), public_key);
+        assert_eq!(verify_package(dir.path(), &[key.verifying_key()], false).unwrap(), public_key);
+    }
+
+    #[test]
+    fn rejects_tampered_files() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = signed_package(&key);
+        fs::write(dir.path().join("StarRail_Data/a.pck.hdiff"), b"evil!").unwrap();
+
+        let result = verify_package(dir.path(), &[key.verifying_key()], false);
+        assert!(matches!(result, Err(SigningError::FileMismatch(path)) if path == "StarRail_Data/a.pck.hdiff"));
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = signed_package(&key);
+        fs::remove_file(dir.path().join("hdiffmap.json")).unwrap();
+
+        let result = verify_package(dir.path(), &[key.verifying_key()], true);
+        assert!(matches!(result, Err(SigningError::FileMismatch(path)) if path == "hdiffmap.json"));
+    }
+
+    #[test]
+    fn rejects_a_changed_listing() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = signed_package(&key);
+        fs::write(dir.path().join("hdiffmap.json"), "{}").unwrap();
+        let path = dir.path().join(SIGNATURE_NAME);
+        let mut signed: PackageSignature = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        signed.files[1] = hash_file(dir.path(), "hdiffmap.json".to_string()).unwrap();
+        fs::write(&path, serde_json::to_string(&signed).unwrap()).unwrap();
+
+        let result = verify_package(dir.path(), &[key.verifying_key()], true);
+        assert!(matches!(result, Err(SigningError::BadSignature)));
+    }
+
+    #[test]
+    fn exact_mode_rejects_unlisted_files() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = signed_package(&key);
+        fs::write(dir.path().join("StarRail_Data/evil.exe"), b"evil").unwrap();
+
+        let result = verify_package(dir.path(), &[key.verifying_key()], true);
+        assert!(matches!(result, Err(SigningError::UnlistedFile(path)) if path == "StarRail_Data/evil.exe"));
+        // Sharing the game folder, the package can't tell game files from planted ones
+        assert!(verify_package(dir.path(), &[key.verifying_key()], false).is_ok());
+    }
+
+    #[test]
+    fn rejects_unlisted_control_files() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = signed_package(&key);
+        fs::write(dir.path().join("deletefiles.txt"), "StarRail_Data/a.pck\n").unwrap();
+
+        let result = verify_package(dir.path(), &[key.verifying_key()], false);
+        assert!(matches!(result, Err(SigningError::UnlistedFile(path)) if path == "deletefiles.txt"));
+    }
+
+    #[test]
+    fn rejects_untrusted_and_missing_signatures() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let dir = signed_package(&key);
+
+        let result = verify_package(dir.path(), &[other.verifying_key()], true);
+        assert!(matches!(result, Err(SigningError::UntrustedKey(public_key)) if public_key == to_hex(key.verifying_key().as_bytes())));
+
+        fs::remove_file(dir.path().join(SIGNATURE_NAME)).unwrap();
+        assert!(matches!(verify_package(dir.path(), &[key.verifying_key()], true), Err(SigningError::Unsigned)));
+    }
+
+    #[test]
+    fn generate_key_keeps_existing_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("team.key");
+        let public_key = generate_key(&path).unwrap();
+        let trusted = load_trusted_keys(&public_key_path(&path)).unwrap();
+        assert_eq!(trusted, vec![load_signing_key(&path).unwrap().verifying_key()]);
+        assert_eq!(to_hex(trusted[0].as_bytes()), public_key);
+
+        assert!(generate_key(&path).is_err());
+        assert_eq!(load_trusted_keys(&public_key_path(&path)).unwrap(), trusted);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
@@ -27,6 +27,47 @@ pub fn run_command_with_nixos_wrapper(executable: &Path, args: &[&str]) -> std::
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkKind {
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+pub fn link_or_copy(source: &Path, target: &Path) -> std::io::Result<LinkKind> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if reflink_copy::reflink(source, target).is_ok() {
+        return Ok(LinkKind::Reflink);
+    }
+    if std::fs::hard_link(source, target).is_ok() {
+        return Ok(LinkKind::Hardlink);
+    }
+    std::fs::copy(source, target)?;
+    Ok(LinkKind::Copy)
+}
+
+pub fn mirror_missing_files(source_root: &Path, target_root: &Path, skip: impl Fn(&str) -> bool) -> std::io::Result<usize> {
+    let files: Vec<_> = walkdir::WalkDir::new(source_root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let rel_path = e.path().strip_prefix(source_root).ok()?.to_string_lossy().replace("\\", "/");
+            (!skip(&rel_path) && !target_root.join(&rel_path).exists()).then(|| (rel_path, e.into_path()))
+        })
+        .collect();
+
+    let pb = create_progress_bar(files.len());
+    for (rel_path, path) in &files {
+        link_or_copy(path, &target_root.join(rel_path))?;
+        pb.inc(1);
+    }
+    pb.finish();
+    Ok(files.len())
+}
+
 static PROGRESS_TEMPLATE: &str = "{spinner:.green} [{elapsed}] [{bar:35.green/bright-black}] {pos}/{len} ({percent}%)";
 static PROGRESS_CHARS: &str = "█>-";
 
@@ -39,3 +80,39 @@ pub fn create_progress_bar(len: usize) -> ProgressBar {
     );
     pb
 }
+
+/// Parses sizes like `4096`, `64k`, `256m` or `2g` the way HDiffPatch's command line does.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim().to_ascii_lowercase();
+    let (digits, shift) = match text.chars().last()? {
+        'k' => (&text[..text.len() - 1], 10),
+        'm' => (&text[..text.len() - 1], 20),
+        'g' => (&text[..text.len() - 1], 30),
+        _ => (text.as_str(), 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+pub fn format_utc(unix_seconds: u64) -> String {
+    let (days, seconds) = (unix_seconds / 86400, unix_seconds % 86400);
+    // Days to civil date, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,157 @@
+use std::{fmt, fs, path::{Path, PathBuf}, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GameVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl fmt::Display for GameVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for GameVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .trim()
+            .split('.')
+            .map(|p| p.parse::<u32>().map_err(|_| format!("Invalid version: {}", s)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match parts.as_slice() {
+            [major, minor] => Ok(Self { major: *major, minor: *minor, patch: 0 }),
+            [major, minor, patch] => Ok(Self { major: *major, minor: *minor, patch: *patch }),
+            _ => Err(format!("Invalid version: {}", s)),
+        }
+    }
+}
+
+pub const CONFIG_FILE: &str = "config.ini";
+static CONFIG_VERSION_KEY: &str = "game_version";
+static BINARY_VERSION_FILE: &str = "StreamingAssets/BinaryVersion.bytes";
+
+pub fn detect_version(game_path: &Path) -> Option<GameVersion> {
+    read_config_version(game_path).or_else(|| read_binary_version(game_path))
+}
+
+pub fn read_config_version(game_path: &Path) -> Option<GameVersion> {
+    let content = fs::read_to_string(game_path.join(CONFIG_FILE)).ok()?;
+    content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == CONFIG_VERSION_KEY)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+pub fn read_binary_version(game_path: &Path) -> Option<GameVersion> {
+    let bytes = fs::read(find_binary_version_file(game_path)?).ok()?;
+    find_version_in_bytes(&bytes)
+}
+
+pub fn write_config_version(game_path: &Path, version: GameVersion) -> std::io::Result<()> {
+    let config_path = game_path.join(CONFIG_FILE);
+    let content = fs::read_to_string(&config_path).unwrap_or_default();
+    let version_line = format!("{}={}", CONFIG_VERSION_KEY, version);
+
+    let mut replaced = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if key.trim() == CONFIG_VERSION_KEY => {
+                replaced = true;
+                version_line.clone()
+            }
+            _ => line.to_string(),
+        })
+        .collect();
+
+    if !replaced {
+        match lines.iter().position(|line| line.trim() == "[General]") {
+            Some(index) => lines.insert(index + 1, version_line),
+            None => {
+                lines.insert(0, "[General]".to_string());
+                lines.insert(1, version_line);
+            }
+        }
+    }
+
+    let temp_path = game_path.join(format!("{}.tmp", CONFIG_FILE));
+    fs::write(&temp_path, lines.join("\n") + "\n")?;
+    fs::rename(temp_path, config_path)
+}
+
+pub fn versions_from_file_name(file_name: &str) -> Option<(GameVersion, GameVersion)> {
+    let versions: Vec<GameVersion> = file_name
+        .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .filter(|part| part.matches('.').count() == 2)
+        .filter_map(|part| part.parse().ok())
+        .collect();
+
+    match versions.as_slice() {
+        [from, to, ..] => Some((*from, *to)),
+        _ => None,
+    }
+}
+
+/// Name of the game at `game_path`, taken from its `<Game>_Data` folder.
+pub fn detect_game(game_path: &Path) -> Option<String> {
+    data_dirs(game_path)
+        .into_iter()
+        .filter_map(|data_dir| Some(data_dir.file_name()?.to_str()?.strip_suffix("_Data")?.to_string()))
+        .min()
+}
+
+fn data_dirs(game_path: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(game_path) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_dir() && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.ends_with("_Data"))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn find_binary_version_file(game_path: &Path) -> Option<PathBuf> {
+    data_dirs(game_path)
+        .into_iter()
+        .map(|data_dir| data_dir.join(BINARY_VERSION_FILE))
+        .find(|path| path.is_file())
+}
+
+fn find_version_in_bytes(bytes: &[u8]) -> Option<GameVersion> {
+    let mut start = 0;
+    while start < bytes.len() {
+        if !bytes[start].is_ascii_digit() {
+            start += 1;
+            continue;
+        }
+
+        let end = bytes[start..]
+            .iter()
+            .position(|b| !(b.is_ascii_digit() || *b == b'.'))
+            .map(|len| start + len)
+            .unwrap_or(bytes.len());
+
+        let candidate = String::from_utf8_lossy(&bytes[start..end]);
+        let candidate = candidate.trim_end_matches('.');
+        if candidate.matches('.').count() == 2
+            && let Ok(version) = candidate.parse()
+        {
+            return Some(version);
+        }
+        start = end;
+    }
+    None
+}
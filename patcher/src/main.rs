@@ -2,19 +2,40 @@
 
 use std::path::{Path, PathBuf};
 
-use crate::options::{hdiff::{handle_hdiff, HdiffHandler}, ldiff::{handle_ldiff, handler::LdiffHandler}};
-
-mod options;
-mod utils;
+use common::embedded::{HPatchz, PatchOptions};
+use patcher::{options, options::signature::SignaturePolicy, utils};
+use patcher::options::{hdiff::{handle_hdiff, handle_hdiff_chain, handle_hdiff_out_of_place, HdiffHandler}, ldiff::{handle_ldiff, handle_ldiff_out_of_place, handler::LdiffHandler}};
 
 fn main() {
     println!("HysilensDownloader by Remi made with love <3");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match PatchOptions::from_args(&args) {
+        Ok(options) => {
+            HPatchz::configure(options);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match SignaturePolicy::from_args(&args) {
+        Ok(policy) => {
+            policy.configure();
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
     println!("Options:");
     println!("0 - Patch game via hdiff");
     println!("1 - Patch game via ldiff");
     println!("2 - Patch game via Sophon (not yet supported)");
     println!("3 - Verify file integrity");
     println!("4 - Delete leftover files");
+    println!("5 - Generate pkg_version");
+    println!("6 - Patch game via several hdiff packages");
+    println!("7 - Patch game into a separate folder");
     
     let input = common::input::read_input("Please select action: ");
     
@@ -46,6 +67,23 @@ fn main() {
                 HdiffHandler::new(Path::new(&game_folder)).remove_deleted_files();
             }
         }
+        "5" => {
+            let game_folder = common::input::read_input("Please enter game folder: ");
+            options::pkg_version::handle_pkg_version(&game_folder);
+        }
+        "6" => {
+            let game_folder = common::input::read_input("Please enter game folder: ");
+            handle_hdiff_chain(&game_folder);
+        }
+        "7" => {
+            let game_folder = common::input::read_input("Please enter game folder: ");
+            let output_folder = common::input::read_input("Please enter output folder: ");
+            match common::input::read_input("Patch type (hdiff/ldiff): ").to_lowercase().as_str() {
+                "hdiff" => handle_hdiff_out_of_place(&game_folder, &output_folder),
+                "ldiff" => handle_ldiff_out_of_place(&game_folder, &output_folder),
+                _ => println!("Patch type is not supported"),
+            }
+        }
         _ => println!("Option is not supported")
     }
 }
\ No newline at end of file
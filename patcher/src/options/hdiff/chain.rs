@@ -0,0 +1,441 @@
+use std::{collections::{HashMap, HashSet}, fs, io::{BufRead, BufReader}, path::{Path, PathBuf}};
+
+use common::{embedded::HPatchz, pkg_version::{PkgVersionEntry, PKG_VERSION_FILE}, safe_path::{self, safe_join}, version::GameVersion};
+
+use crate::options::package_info::PACKAGE_INFO_NAME;
+use crate::options::hdiff::{FileOpKind, HdiffHandler, DIR_DIFF_NAME, FILE_OPS_NAME};
+use crate::utils::{self, HdiffUpdateMode};
+
+/// How many unverified files the chain names before summing up the rest.
+const MAX_UNVERIFIED_LISTED: usize = 20;
+
+#[derive(Clone)]
+enum FileBase {
+    Install(String),
+    Package(PathBuf),
+}
+
+#[derive(Clone)]
+struct FilePlan {
+    base: FileBase,
+    patches: Vec<PathBuf>,
+    target_md5: Option<String>,
+    target_size: Option<u64>,
+}
+
+impl FilePlan {
+    fn unchanged(rel_path: &str) -> Self {
+        Self { base: FileBase::Install(rel_path.to_string()), patches: Vec::new(), target_md5: None, target_size: None }
+    }
+}
+
+pub struct HdiffChain<'a> {
+    pub game_path: &'a Path,
+    staging_path: PathBuf,
+    plans: HashMap<String, Option<FilePlan>>,
+}
+
+impl<'a> HdiffChain<'a> {
+    pub fn new(game_path: &'a Path) -> Self {
+        Self { game_path, staging_path: game_path.join("hdiff_chain"), plans: HashMap::new() }
+    }
+
+    pub fn apply(&mut self, packages: &[PathBuf]) -> bool {
+        for (index, package) in packages.iter().enumerate() {
+            let package_dir = self.staging_path.join(index.to_string());
+            println!("Extracting {}...", package.display());
+            if let Err(e) = common::archive::extract_verified(package, &package_dir) {
+                eprintln!("Failed to extract {}: {}", package.display(), e);
+                self.clean();
+                return false;
+            }
+            if let Err(e) = self.add_package(&package_dir) {
+                eprintln!("Failed to read {}: {}", package.display(), e);
+                self.clean();
+                return false;
+            }
+        }
+
+        let success = self.check_sources() && self.build_and_commit();
+        self.clean();
+        success
+    }
+
+    fn add_package(&mut self, package_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !crate::options::signature::check_package(package_dir, true) {
+            return Err("signature check failed".into());
+        }
+        let handler = HdiffHandler::new(package_dir);
+        if handler.read_dir_diff()?.is_some() {
+            return Err(format!("{} contains a directory diff, which can't be chained", DIR_DIFF_NAME).into());
+        }
+        let mut referenced = HashSet::new();
+        let mut updates = Vec::new();
+        let mut consumed = Vec::new();
+
+        if let Some(ops) = handler.read_file_ops()? {
+            for op in ops.operations {
+                let source_name = normalize(&op.source_file_name)?;
+                let target_name = normalize(&op.target_file_name)?;
+                let mut plan = self.current_plan(&source_name).ok_or_else(|| {
+                    format!("{} is deleted by an earlier package", source_name)
+                })?;
+                plan.target_md5 = Some(op.file_md5);
+                plan.target_size = Some(op.file_size);
+                if op.kind == FileOpKind::Move {
+                    consumed.push(source_name);
+                }
+                updates.push((target_name, plan));
+            }
+            referenced.insert(package_dir.join(FILE_OPS_NAME));
+        }
+
+        match utils::detect_hdiff_update_type(&package_dir.to_path_buf()) {
+            HdiffUpdateMode::Hdiffmap => {
+                let map = handler.read_hdiffmap()?;
+                for entry in map.diff_map {
+                    let patch = safe_join(package_dir, &entry.patch_file_name)?;
+                    let source_name = normalize(&entry.source_file_name)?;
+                    let target_name = normalize(&entry.target_file_name)?;
+                    let mut plan = self.current_plan(&source_name).ok_or_else(|| {
+                        format!("{} is deleted by an earlier package", source_name)
+                    })?;
+                    plan.patches.push(patch.clone());
+                    plan.target_md5 = Some(entry.target_file_md5);
+                    plan.target_size = Some(entry.target_file_size);
+                    referenced.insert(patch);
+                    if source_name != target_name {
+                        consumed.push(source_name);
+                    }
+                    updates.push((target_name, plan));
+                }
+                for entry in map.copy_map {
+                    let payload = safe_join(package_dir, &entry.payload_file_name)?;
+                    updates.push((normalize(&entry.target_file_name)?, FilePlan {
+                        base: FileBase::Package(payload.clone()),
+                        patches: Vec::new(),
+                        target_md5: Some(entry.file_md5),
+                        target_size: Some(entry.file_size),
+                    }));
+                    referenced.insert(payload);
+                }
+                referenced.insert(package_dir.join("hdiffmap.json"));
+            }
+            HdiffUpdateMode::Hdifffiles => {
+                for entry in handler.read_hdifffiles()? {
+                    let remote_name = normalize(&entry.remote_name)?;
+                    let patch = safe_join(package_dir, &format!("{}.hdiff", remote_name))?;
+                    let mut plan = self.current_plan(&remote_name).ok_or_else(|| {
+                        format!("{} is deleted by an earlier package", remote_name)
+                    })?;
+                    plan.patches.push(patch.clone());
+                    plan.target_md5 = None;
+                    plan.target_size = None;
+                    referenced.insert(patch);
+                    updates.push((remote_name, plan));
+                }
+                referenced.insert(package_dir.join("hdifffiles.txt"));
+                referenced.insert(package_dir.join("README.txt"));
+            }
+            HdiffUpdateMode::None => return Err("package contains no hdiffmap.json or hdifffiles.txt".into()),
+        }
+
+        let targets: HashSet<_> = updates.iter().map(|(target, _)| target.clone()).collect();
+        for source in consumed.into_iter().filter(|source| !targets.contains(source)) {
+            self.plans.insert(source, None);
+        }
+        for (target, plan) in updates {
+            self.plans.insert(target, Some(plan));
+        }
+
+        let deletefiles_path = package_dir.join("deletefiles.txt");
+        referenced.insert(deletefiles_path.clone());
+        referenced.insert(package_dir.join(PACKAGE_INFO_NAME));
+        referenced.insert(package_dir.join(common::signing::SIGNATURE_NAME));
+        for entry in walkdir::WalkDir::new(package_dir).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() || referenced.contains(entry.path()) {
+                continue;
+            }
+            let rel_path = entry.path().strip_prefix(package_dir)?.to_string_lossy().replace("\\", "/");
+            self.plans.insert(rel_path, Some(FilePlan {
+                base: FileBase::Package(entry.path().to_path_buf()),
+                patches: Vec::new(),
+                target_md5: None,
+                target_size: None,
+            }));
+        }
+
+        if let Ok(file) = fs::File::open(&deletefiles_path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    self.plans.insert(normalize(&line)?, None);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn current_plan(&self, rel_path: &str) -> Option<FilePlan> {
+        match self.plans.get(rel_path) {
+            Some(plan) => plan.clone(),
+            None => Some(FilePlan::unchanged(rel_path)),
+        }
+    }
+
+    fn check_sources(&self) -> bool {
+        let missing = self.plans
+            .values()
+            .flatten()
+            .filter(|plan| match &plan.base {
+                FileBase::Install(rel_path) => !safe_join(self.game_path, rel_path).map(|p| p.is_file()).unwrap_or(false),
+                FileBase::Package(_) => false,
+            })
+            .count();
+
+        if missing == 0 {
+            return true;
+        }
+
+        eprintln!("{} source files are missing, these packages probably don't match the installed version", missing);
+        common::input::confirm("Continue anyway?")
+    }
+
+    fn build_and_commit(&self) -> bool {
+        let hpatchz = match HPatchz::instance() {
+            Ok(h) => h,
+            Err(e) => {
+                eprintln!("Failed to get HPatchz instance: {}", e);
+                return false;
+            }
+        };
+
+        let output_path = self.staging_path.join("output");
+        let changed: Vec<_> = self.plans
+            .iter()
+            .filter_map(|(rel_path, plan)| plan.as_ref().map(|plan| (rel_path, plan)))
+            .filter(|(rel_path, plan)| !matches!(&plan.base, FileBase::Install(base) if base == *rel_path && plan.patches.is_empty()))
+            .collect();
+
+        println!("Building {} files...", changed.len());
+        let pb = common::utils::create_progress_bar(changed.len());
+        let pkg_version = self.final_pkg_version();
+        let mut built = Vec::new();
+        let mut unverified = Vec::new();
+        let mut failed = 0;
+
+        for (index, (rel_path, plan)) in changed.into_iter().enumerate() {
+            // hdifffiles packages carry no hashes, so their files are checked against pkg_version when it has them
+            let mut plan = plan.clone();
+            if plan.target_md5.is_none()
+                && let Some(entry) = pkg_version.get(rel_path.as_str())
+            {
+                plan.target_md5 = Some(entry.md5.clone());
+                plan.target_size = Some(entry.file_size);
+            }
+            // pkg_version itself is only as good as the package it came from
+            if plan.target_md5.is_none() && (rel_path != PKG_VERSION_FILE || pkg_version.is_empty()) {
+                unverified.push(rel_path.as_str());
+            }
+            let result = self.build_file(hpatchz, &plan, &output_path.join(index.to_string()));
+            match result {
+                Ok(path) => built.push((rel_path, path)),
+                Err(e) => {
+                    pb.suspend(|| eprintln!("Failed to build {}: {}", rel_path, e));
+                    failed += 1;
+                }
+            }
+            pb.inc(1);
+        }
+        pb.finish();
+
+        if failed > 0 {
+            eprintln!("{} files failed to build, the install was left untouched", failed);
+            return false;
+        }
+        if !confirm_unverified(&mut unverified) {
+            eprintln!("The install was left untouched");
+            return false;
+        }
+
+        println!("Applying {} files...", built.len());
+        for (rel_path, path) in built {
+            let target = match safe_join(self.game_path, rel_path) {
+                Ok(target) => target,
+                Err(e) => {
+                    eprintln!("Refusing to replace {}: {}", rel_path, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            if let Some(parent) = target.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            if let Err(e) = fs::rename(&path, &target).or_else(|_| fs::copy(&path, &target).map(|_| ())) {
+                eprintln!("Failed to replace {}: {}", target.display(), e);
+                failed += 1;
+            }
+        }
+
+        println!("Deleting files...");
+        for rel_path in self.plans.iter().filter(|(_, plan)| plan.is_none()).map(|(rel_path, _)| rel_path) {
+            let file_path = match safe_join(self.game_path, rel_path) {
+                Ok(path) => path,
+                Err(e) => {
+                    eprintln!("Refusing to delete {}: {}", rel_path, e);
+                    continue;
+                }
+            };
+            if file_path.exists() && let Err(e) = fs::remove_file(&file_path) {
+                eprintln!("Failed to delete {}: {}", file_path.display(), e);
+            }
+        }
+
+        println!("Done!");
+        failed == 0
+    }
+
+    /// Entries of the pkg_version the chain ends with, when a package ships it as is.
+    fn final_pkg_version(&self) -> HashMap<String, PkgVersionEntry> {
+        let Some(Some(FilePlan { base: FileBase::Package(path), patches, .. })) = self.plans.get(PKG_VERSION_FILE) else {
+            return HashMap::new();
+        };
+        if !patches.is_empty() {
+            return HashMap::new();
+        }
+        fs::read_to_string(path)
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<PkgVersionEntry>(line).ok())
+                    .map(|entry| (entry.remote_name.clone(), entry))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn build_file(&self, hpatchz: &HPatchz, plan: &FilePlan, output: &Path) -> Result<PathBuf, String> {
+        let mut current = match &plan.base {
+            FileBase::Install(rel_path) => safe_join(self.game_path, rel_path).map_err(|e| e.to_string())?,
+            FileBase::Package(path) => path.clone(),
+        };
+
+        fs::create_dir_all(output).map_err(|e| e.to_string())?;
+        for (step, patch) in plan.patches.iter().enumerate() {
+            let next = output.join(step.to_string());
+            hpatchz.patch_to(&current, patch, &next).map_err(|e| e.to_string())?;
+            if current.starts_with(output) {
+                let _ = fs::remove_file(&current);
+            }
+            current = next;
+        }
+
+        if let Some(target_size) = plan.target_size {
+            let size = current.metadata().map(|m| m.len()).unwrap_or(0);
+            if size != target_size {
+                return Err(format!("expected size {}, got {}", target_size, size));
+            }
+        }
+        if let Some(target_md5) = &plan.target_md5 {
+            let md5 = common::md5::calculate_md5(&current).map_err(|e| e.to_string())?;
+            if &md5 != target_md5 {
+                return Err(format!("expected MD5 {}, got {}", target_md5, md5));
+            }
+        }
+
+        // Package files are copied too, since one payload can back several targets
+        if current.starts_with(output) {
+            Ok(current)
+        } else {
+            let copy = output.join("copy");
+            fs::copy(&current, &copy).map_err(|e| e.to_string())?;
+            Ok(copy)
+        }
+    }
+
+    fn clean(&self) {
+        if self.staging_path.exists() {
+            let _ = fs::remove_dir_all(&self.staging_path);
+        }
+    }
+}
+
+/// Lists the files nothing in the chain could check and asks whether to apply them anyway.
+fn confirm_unverified(unverified: &mut [&str]) -> bool {
+    if unverified.is_empty() {
+        return true;
+    }
+
+    unverified.sort();
+    eprintln!("Warning: {} files could not be verified, as no package in the chain has hashes for them:", unverified.len());
+    for rel_path in unverified.iter().take(MAX_UNVERIFIED_LISTED) {
+        eprintln!("  {}", rel_path);
+    }
+    if unverified.len() > MAX_UNVERIFIED_LISTED {
+        eprintln!("  ...and {} more", unverified.len() - MAX_UNVERIFIED_LISTED);
+    }
+    common::input::confirm("Apply them anyway?")
+}
+
+fn normalize(rel_path: &str) -> Result<String, safe_path::PathError> {
+    Ok(safe_path::sanitize(rel_path)?.to_string_lossy().replace("\\", "/"))
+}
+
+pub fn handle_hdiff_chain(game_path: &str) {
+    let game_path = PathBuf::from(game_path);
+    if !game_path.exists() {
+        eprintln!("Could not find folder {}", game_path.display());
+        return;
+    }
+
+    println!("Enter hdiff archive locations in update order, leave empty to finish");
+    let mut packages = Vec::new();
+    loop {
+        let input = common::input::read_input(&format!("Package {}: ", packages.len() + 1));
+        if input.is_empty() {
+            break;
+        }
+        let package = PathBuf::from(input);
+        if !common::archive::exists(&package) {
+            eprintln!("Could not find file {}", package.display());
+            continue;
+        }
+        packages.push(package);
+    }
+
+    if packages.is_empty() {
+        eprintln!("No packages given");
+        return;
+    }
+
+    let versions: Vec<Option<(GameVersion, GameVersion)>> = packages
+        .iter()
+        .map(|p| common::archive::base_path(p).file_name().and_then(|name| common::version::versions_from_file_name(&name.to_string_lossy())))
+        .collect();
+
+    for (pair, window) in versions.windows(2).zip(packages.windows(2)) {
+        if let [Some((_, previous_target)), Some((next_source, _))] = pair
+            && previous_target != next_source
+        {
+            eprintln!(
+                "{} updates to {}, but {} expects {}",
+                window[0].display(), previous_target, window[1].display(), next_source
+            );
+            if !common::input::confirm("Continue anyway?") {
+                return;
+            }
+        }
+    }
+
+    if let Some(Some((source_version, _))) = versions.first()
+        && !utils::check_version_applicable(&game_path, *source_version)
+    {
+        return;
+    }
+
+    if HdiffChain::new(&game_path).apply(&packages) {
+        let target_version = versions.last().copied().flatten().map(|(_, target_version)| target_version);
+        utils::update_config_version(&game_path, target_version);
+    }
+}
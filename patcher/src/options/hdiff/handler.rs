@@ -1,28 +1,67 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, BufRead};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use serde_json::from_str;
-use common::embedded::HPatchz;
+use common::embedded::{hpatchz::PatchError, HPatchz};
+use common::safe_path::{safe_join, PathError};
+use common::signing::SIGNATURE_NAME;
 
-use crate::options::hdiff::{HdiffFilesEntry, HdiffMap, HdiffUpdateMode};
+use crate::options::hdiff::{CopyEntry, DirDiff, FileOp, FileOpKind, FileOps, HdiffFilesEntry, HdiffMap, HdiffMapEntry, HdiffUpdateMode, DIR_DIFF_NAME, FILE_OPS_NAME};
+use crate::options::package_info::PACKAGE_INFO_NAME;
 use crate::utils;
 
 pub struct HdiffHandler<'a> {
     pub game_path: &'a Path,
+    pub source_path: &'a Path,
 }
 
 impl<'a> HdiffHandler<'a> {
     pub fn new(game_path: &'a Path) -> Self {
-        Self { game_path }
+        Self { game_path, source_path: game_path }
+    }
+    
+    pub fn with_source(game_path: &'a Path, source_path: &'a Path) -> Self {
+        Self { game_path, source_path }
+    }
+    
+    fn is_out_of_place(&self) -> bool {
+        self.source_path != self.game_path
+    }
+    
+    fn run_patch(&self, hpatchz: &HPatchz, source: &Path, patch: &Path, target: &Path) -> Result<(), PatchError> {
+        if !self.is_out_of_place() {
+            return hpatchz.patch(&source.to_path_buf(), &patch.to_path_buf(), &target.to_path_buf());
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        hpatchz.patch_to(source, patch, target)?;
+        hpatchz.remove_file(&patch.to_path_buf());
+        Ok(())
+    }
+    
+    fn mirror_source(&self, skip: &HashSet<String>) {
+        if !self.is_out_of_place() {
+            return;
+        }
+        println!("Linking unchanged files...");
+        if let Err(e) = common::utils::mirror_missing_files(self.source_path, self.game_path, |rel_path| skip.contains(rel_path)) {
+            eprintln!("Failed to link unchanged files: {}", e);
+        }
     }
 
-    pub fn apply(&self) {
-        match utils::detect_hdiff_update_type(&self.game_path.to_path_buf()) {
+    pub fn apply(&self) -> bool {
+        let success = match utils::detect_hdiff_update_type(&self.game_path.to_path_buf()) {
             HdiffUpdateMode::Hdifffiles => self.apply_hdifffiles(),
             HdiffUpdateMode::Hdiffmap => self.apply_hdiffmap(),
-            HdiffUpdateMode::None => eprintln!("No hdiff update found at {}", self.game_path.display()),
-        }
+            HdiffUpdateMode::None => {
+                eprintln!("No hdiff update found at {}", self.game_path.display());
+                false
+            }
+        };
         self.remove_hdiff_files();
+        success
     }
     
     fn remove_hdiff_files(&self) {
@@ -34,14 +73,19 @@ impl<'a> HdiffHandler<'a> {
             }
         };
         hpatchz.remove_file(&self.game_path.join("deletefiles.txt"));
+        for name in [PACKAGE_INFO_NAME, SIGNATURE_NAME] {
+            if self.game_path.join(name).exists() {
+                hpatchz.remove_file(&self.game_path.join(name));
+            }
+        }
     }
 
-    fn apply_hdifffiles(&self) {
+    fn apply_hdifffiles(&self) -> bool {
         let entries = match self.read_hdifffiles() {
             Ok(e) => e,
             Err(e) => {
                 eprintln!("Failed to read hdifffiles.txt: {}", e);
-                return;
+                return false;
             }
         };
 
@@ -49,31 +93,43 @@ impl<'a> HdiffHandler<'a> {
             Ok(h) => h,
             Err(e) => {
                 eprintln!("Failed to get HPatchz instance: {}", e);
-                return;
+                return false;
             }
         };
         
         println!("Patching files via hdifffiles.txt method...");
         let pb = common::utils::create_progress_bar(entries.len());
+        let mut failed = 0;
 
         for entry in entries {
-            let source = self.game_path.join(&entry.remote_name);
-            let patch_file = source.with_file_name(format!(
+            let (source, target) = match (safe_join(self.source_path, &entry.remote_name), safe_join(self.game_path, &entry.remote_name)) {
+                (Ok(source), Ok(target)) => (source, target),
+                (Err(e), _) | (_, Err(e)) => {
+                    pb.suspend(|| eprintln!("Refusing to patch {}: {}", entry.remote_name, e));
+                    pb.inc(1);
+                    failed += 1;
+                    continue;
+                }
+            };
+            let patch_file = target.with_file_name(format!(
                 "{}.hdiff",
-                source.file_name().unwrap().to_string_lossy()
+                target.file_name().unwrap().to_string_lossy()
             ));
-            if let Err(e) = hpatchz.patch(&source, &patch_file, &source) {
+            if let Err(e) = self.run_patch(hpatchz, &source, &patch_file, &target) {
                 pb.suspend(|| eprintln!("Failed to patch {}: {}", source.display(), e));
+                failed += 1;
             }
             pb.inc(1);
         }
         pb.finish();
+        self.mirror_source(&self.read_deleted_files().into_iter().collect());
         self.remove_deleted_files();
         hpatchz.remove_file(&self.game_path.join("hdifffiles.txt"));
         hpatchz.remove_file(&self.game_path.join("README.txt"));
+        failed == 0
     }
 
-    fn read_hdifffiles(&self) -> Result<Vec<HdiffFilesEntry>, Box<dyn std::error::Error>> {
+    pub(super) fn read_hdifffiles(&self) -> Result<Vec<HdiffFilesEntry>, Box<dyn std::error::Error>> {
         let path = self.game_path.join("hdifffiles.txt");
         let file = fs::File::open(path)?;
         let reader = io::BufReader::new(file);
@@ -88,12 +144,12 @@ impl<'a> HdiffHandler<'a> {
         Ok(entries)
     }
 
-    fn apply_hdiffmap(&self) {
+    fn apply_hdiffmap(&self) -> bool {
         let map = match self.read_hdiffmap() {
             Ok(m) => m,
             Err(e) => {
                 eprintln!("Failed to read or parse hdiffmap.json: {}", e);
-                return;
+                return false;
             }
         };
 
@@ -101,23 +157,49 @@ impl<'a> HdiffHandler<'a> {
             Ok(h) => h,
             Err(e) => {
                 eprintln!("Failed to get HPatchz instance: {}", e);
-                return;
+                return false;
             }
         };
         
+        if !self.check_sources(&map) {
+            return false;
+        }
+        
         println!("Patching files via hdiffmap.json method");
         let pb = common::utils::create_progress_bar(map.diff_map.len());
+        let mut failed = 0;
+        let mut skip_mirror: HashSet<String> = self.read_deleted_files().into_iter().collect();
+        let targets: HashSet<_> = map.diff_map.iter().map(|entry| entry.target_file_name.clone()).collect();
+        skip_mirror.extend(
+            map.diff_map
+                .iter()
+                .filter(|entry| !targets.contains(&entry.source_file_name))
+                .map(|entry| entry.source_file_name.clone())
+        );
+
+        let (ops_failed, moved) = self.apply_file_ops();
+        failed += ops_failed;
+        skip_mirror.extend(moved);
+        failed += self.apply_dir_diff(hpatchz);
+        failed += self.apply_copy_map(&map.copy_map);
 
         for entry in map.diff_map {
-            let source = self.game_path.join(entry.source_file_name);
-            let patch = self.game_path.join(entry.patch_file_name);
-            let target = self.game_path.join(entry.target_file_name);
+            let (source, patch, target) = match self.resolve_entry(&entry) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    pb.suspend(|| eprintln!("Refusing to patch {}: {}", entry.target_file_name, e));
+                    pb.inc(1);
+                    failed += 1;
+                    continue;
+                }
+            };
             
             let source_md5 = match common::md5::calculate_md5(&source) {
                 Ok(md5) => md5,
                 Err(e) => {
                     pb.suspend(|| eprintln!("Source file error {}: {}", source.display(), e));
                     pb.inc(1);
+                    failed += 1;
                     continue;
                 }
             };
@@ -126,6 +208,7 @@ impl<'a> HdiffHandler<'a> {
             if source_md5 != entry.source_file_md5 || source_size != entry.source_file_size {
                 pb.suspend(|| eprintln!("Source file invalid: {}", source.display()));
                 pb.inc(1);
+                failed += 1;
                 continue;
             }
             let patch_md5 = match common::md5::calculate_md5(&patch) {
@@ -133,6 +216,7 @@ impl<'a> HdiffHandler<'a> {
                 Err(e) => {
                     pb.suspend(|| eprintln!("Patch file error {}: {}", patch.display(), e));
                     pb.inc(1);
+                    failed += 1;
                     continue;
                 }
             };
@@ -140,12 +224,14 @@ impl<'a> HdiffHandler<'a> {
             if patch_md5 != entry.patch_file_md5 || patch_size != entry.patch_file_size {
                 pb.suspend(|| eprintln!("Patch file invalid: {}", patch.display()));
                 pb.inc(1);
+                failed += 1;
                 continue;
             }    
 
-            if let Err(e) = hpatchz.patch(&source, &patch, &target) {
+            if let Err(e) = self.run_patch(hpatchz, &source, &patch, &target) {
                 pb.suspend(|| eprintln!("Failed to patch {}: {}", source.display(), e));
                 pb.inc(1);
+                failed += 1;
                 continue;
             }
             
@@ -154,27 +240,244 @@ impl<'a> HdiffHandler<'a> {
                 Err(e) => {
                     pb.suspend(|| eprintln!("Target file error {}: {}", target.display(), e));
                     pb.inc(1);
+                    failed += 1;
                     continue;
                 }
             };
             let target_size = target.metadata().map(|m| m.len()).unwrap_or(0);
             if target_md5 != entry.target_file_md5 || target_size != entry.target_file_size {
                 pb.suspend(|| eprintln!("Patched file does not match expected MD5/size: {}", target.display()));
+                failed += 1;
             }
             pb.inc(1);
         }
 
+        self.mirror_source(&skip_mirror);
         self.remove_deleted_files();
         hpatchz.remove_file(&self.game_path.join("hdiffmap.json"));
+        if self.game_path.join(FILE_OPS_NAME).exists() {
+            hpatchz.remove_file(&self.game_path.join(FILE_OPS_NAME));
+        }
+        failed == 0
+    }
+
+    /// Places every shipped file from its payload, checking each payload once, and returns the
+    /// failure count.
+    fn apply_copy_map(&self, entries: &[CopyEntry]) -> usize {
+        if entries.is_empty() {
+            return 0;
+        }
+
+        println!("Placing shipped files...");
+        let pb = common::utils::create_progress_bar(entries.len());
+        let mut failed = 0;
+        let mut payloads: HashMap<&str, Result<PathBuf, String>> = HashMap::new();
+
+        for entry in entries {
+            let payload = payloads
+                .entry(entry.payload_file_name.as_str())
+                .or_insert_with(|| check_payload(self.game_path, entry))
+                .clone();
+            let result = payload.and_then(|payload| {
+                let target = safe_join(self.game_path, &entry.target_file_name).map_err(|e| e.to_string())?;
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                }
+                // Copied rather than linked so targets sharing a payload stay independent files
+                let _ = fs::remove_file(&target);
+                fs::copy(&payload, &target).map(|_| ()).map_err(|e| e.to_string())
+            });
+            if let Err(e) = result {
+                pb.suspend(|| eprintln!("Failed to place {}: {}", entry.target_file_name, e));
+                failed += 1;
+            }
+            pb.inc(1);
+        }
+        pb.finish();
+
+        for payload in payloads.into_values().flatten() {
+            let _ = fs::remove_file(&payload);
+            if let Some(parent) = payload.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        failed
+    }
+
+    /// Applies the package's directory diff into a staging folder and moves the result over
+    /// the subtree, returning the failure count.
+    fn apply_dir_diff(&self, hpatchz: &HPatchz) -> usize {
+        let dir_diff = match self.read_dir_diff() {
+            Ok(Some(dir_diff)) => dir_diff,
+            Ok(None) => return 0,
+            Err(e) => {
+                eprintln!("Failed to read or parse {}: {}", DIR_DIFF_NAME, e);
+                return 1;
+            }
+        };
+
+        let paths = (
+            subtree_path(self.source_path, &dir_diff.subtree),
+            safe_join(self.game_path, &dir_diff.patch_file_name),
+            subtree_path(self.game_path, &dir_diff.subtree),
+        );
+        let (source_dir, patch, target_dir) = match paths {
+            (Ok(source_dir), Ok(patch), Ok(target_dir)) => (source_dir, patch, target_dir),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                eprintln!("Refusing to apply directory diff: {}", e);
+                return 1;
+            }
+        };
+
+        println!("Applying directory diff to {}...", target_dir.display());
+        let staging = self.game_path.join(".dirdiff_staging");
+        let _ = fs::remove_dir_all(&staging);
+        let result = hpatchz
+            .patch_dir(&source_dir, &patch, &staging)
+            .map_err(|e| e.to_string())
+            .and_then(|()| utils::move_tree(&staging, &target_dir).map_err(|e| e.to_string()));
+        let _ = fs::remove_dir_all(&staging);
+
+        match result {
+            Ok(moved) => {
+                println!("Directory diff wrote {} files", moved);
+                hpatchz.remove_file(&patch);
+                hpatchz.remove_file(&self.game_path.join(DIR_DIFF_NAME));
+                0
+            }
+            Err(e) => {
+                eprintln!("Failed to apply directory diff: {}", e);
+                1
+            }
+        }
+    }
+
+    pub(super) fn read_dir_diff(&self) -> Result<Option<DirDiff>, Box<dyn std::error::Error>> {
+        let path = self.game_path.join(DIR_DIFF_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json_data = fs::read_to_string(path)?;
+        Ok(Some(from_str(&json_data)?))
+    }
+
+    /// Runs the package's copy and move operations, returning the failure count and the moved sources.
+    fn apply_file_ops(&self) -> (usize, Vec<String>) {
+        let ops = match self.read_file_ops() {
+            Ok(Some(ops)) => ops.operations,
+            Ok(None) => return (0, Vec::new()),
+            Err(e) => {
+                eprintln!("Failed to read or parse {}: {}", FILE_OPS_NAME, e);
+                return (1, Vec::new());
+            }
+        };
+
+        println!("Copying and moving files...");
+        let pb = common::utils::create_progress_bar(ops.len());
+        let mut failed = 0;
+        let mut moved = Vec::new();
+
+        for op in ops {
+            let (source, target) = match (safe_join(self.source_path, &op.source_file_name), safe_join(self.game_path, &op.target_file_name)) {
+                (Ok(source), Ok(target)) => (source, target),
+                (Err(e), _) | (_, Err(e)) => {
+                    pb.suspend(|| eprintln!("Refusing to create {}: {}", op.target_file_name, e));
+                    pb.inc(1);
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            match self.run_file_op(&op, &source, &target) {
+                Ok(()) if op.kind == FileOpKind::Move => moved.push(op.source_file_name),
+                Ok(()) => {}
+                Err(e) => {
+                    pb.suspend(|| eprintln!("Failed to create {} from {}: {}", target.display(), source.display(), e));
+                    failed += 1;
+                }
+            }
+            pb.inc(1);
+        }
+        pb.finish();
+        (failed, moved)
+    }
+
+    fn run_file_op(&self, op: &FileOp, source: &Path, target: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let source_size = source.metadata()?.len();
+        if source_size != op.file_size || common::md5::calculate_md5(&source.to_path_buf())? != op.file_md5 {
+            return Err("source file does not match expected MD5/size".into());
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if op.kind == FileOpKind::Move && !self.is_out_of_place() {
+            fs::rename(source, target)?;
+        } else {
+            fs::copy(source, target)?;
+        }
+        Ok(())
     }
 
-    fn read_hdiffmap(&self) -> Result<HdiffMap, Box<dyn std::error::Error>> {
+    pub(super) fn read_file_ops(&self) -> Result<Option<FileOps>, Box<dyn std::error::Error>> {
+        let path = self.game_path.join(FILE_OPS_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json_data = fs::read_to_string(path)?;
+        Ok(Some(from_str(&json_data)?))
+    }
+    
+    fn resolve_entry(&self, entry: &HdiffMapEntry) -> Result<(PathBuf, PathBuf, PathBuf), PathError> {
+        Ok((
+            safe_join(self.source_path, &entry.source_file_name)?,
+            safe_join(self.game_path, &entry.patch_file_name)?,
+            safe_join(self.game_path, &entry.target_file_name)?,
+        ))
+    }
+    
+    fn check_sources(&self, map: &HdiffMap) -> bool {
+        let mismatched = map.diff_map
+            .iter()
+            .filter(|entry| {
+                safe_join(self.source_path, &entry.source_file_name)
+                    .ok()
+                    .and_then(|source| source.metadata().ok())
+                    .map(|m| m.len() != entry.source_file_size)
+                    .unwrap_or(true)
+            })
+            .count();
+        
+        if mismatched == 0 {
+            return true;
+        }
+        
+        eprintln!(
+            "{} of {} source files are missing or have an unexpected size, this patch probably doesn't match the installed version",
+            mismatched,
+            map.diff_map.len()
+        );
+        common::input::confirm("Continue anyway?")
+    }
+
+    pub(super) fn read_hdiffmap(&self) -> Result<HdiffMap, Box<dyn std::error::Error>> {
         let path = self.game_path.join("hdiffmap.json");
         let json_data = fs::read_to_string(path)?;
         let map: HdiffMap = from_str(&json_data)?;
         Ok(map)
     }
 
+    fn read_deleted_files(&self) -> Vec<String> {
+        match fs::read_to_string(self.game_path.join("deletefiles.txt")) {
+            Ok(content) => content
+                .lines()
+                .map(|line| line.trim().replace("\\", "/"))
+                .filter(|line| !line.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
     pub fn remove_deleted_files(&self) {
         println!("Deleting files...");
         let deletefiles_path = self.game_path.join("deletefiles.txt");
@@ -191,13 +494,23 @@ impl<'a> HdiffHandler<'a> {
         let pb = common::utils::create_progress_bar(lines.len());
     
         for line in lines {
-            let clean_line = line.trim().replace("\\", "/");
-            let file_path = self.game_path.join(&clean_line);
+            if line.trim().is_empty() {
+                pb.inc(1);
+                continue;
+            }
+            let file_path = match safe_join(self.game_path, &line) {
+                Ok(path) => path,
+                Err(e) => {
+                    pb.suspend(|| eprintln!("Refusing to delete {}: {}", line.trim(), e));
+                    pb.inc(1);
+                    continue;
+                }
+            };
             if file_path.exists() {
                 if let Err(e) = std::fs::remove_file(&file_path) {
                     pb.suspend(|| eprintln!("Failed to delete {}: {}", file_path.display(), e));
                 }
-            } else {
+            } else if !self.is_out_of_place() {
                 pb.suspend(|| println!("Already gone: {}", file_path.display()));
             }
             pb.inc(1);
@@ -207,3 +520,21 @@ impl<'a> HdiffHandler<'a> {
         println!("Done!");
     }
 }
+
+fn check_payload(game_path: &Path, entry: &CopyEntry) -> Result<PathBuf, String> {
+    let payload = safe_join(game_path, &entry.payload_file_name).map_err(|e| e.to_string())?;
+    let size = payload.metadata().map_err(|e| format!("payload {}: {}", entry.payload_file_name, e))?.len();
+    let md5 = common::md5::calculate_md5(&payload).map_err(|e| e.to_string())?;
+    if size != entry.file_size || md5 != entry.file_md5 {
+        return Err(format!("payload {} does not match expected MD5/size", entry.payload_file_name));
+    }
+    Ok(payload)
+}
+
+fn subtree_path(root: &Path, subtree: &str) -> Result<PathBuf, PathError> {
+    if subtree.trim_matches(['.', '/', '\\']).is_empty() {
+        return Ok(root.to_path_buf());
+    }
+    safe_join(root, subtree)
+}
+
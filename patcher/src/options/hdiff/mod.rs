@@ -1,10 +1,12 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use serde::Deserialize;
-use common::embedded::SevenZip;
 
+mod chain;
 mod handler;
+pub use chain::handle_hdiff_chain;
 pub use handler::HdiffHandler;
 
+use crate::options::package_info::{PackageInfo, PACKAGE_INFO_NAME};
 use crate::utils::HdiffUpdateMode;
 
 
@@ -16,7 +18,9 @@ pub struct HdiffFilesEntry {
 
 #[derive(Deserialize)]
 pub struct HdiffMap {
-    pub diff_map: Vec<HdiffMapEntry>
+    pub diff_map: Vec<HdiffMapEntry>,
+    #[serde(default)]
+    pub copy_map: Vec<CopyEntry>
 }
 
 #[derive(Deserialize)]
@@ -32,6 +36,47 @@ pub struct HdiffMapEntry {
     pub patch_file_size: u64
 }
 
+/// A file shipped whole. Targets with the same content share one payload.
+#[derive(Deserialize)]
+pub struct CopyEntry {
+    pub target_file_name: String,
+    pub file_md5: String,
+    pub file_size: u64,
+    pub payload_file_name: String
+}
+
+pub const FILE_OPS_NAME: &str = "fileops.json";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOpKind {
+    Copy,
+    Move,
+}
+
+#[derive(Deserialize)]
+pub struct FileOps {
+    pub operations: Vec<FileOp>
+}
+
+#[derive(Deserialize)]
+pub struct FileOp {
+    pub kind: FileOpKind,
+    pub source_file_name: String,
+    pub target_file_name: String,
+    pub file_md5: String,
+    pub file_size: u64
+}
+
+pub const DIR_DIFF_NAME: &str = "dirdiff.json";
+
+/// A single hdiffz directory diff covering `subtree`, empty for the whole client.
+#[derive(Deserialize)]
+pub struct DirDiff {
+    pub subtree: String,
+    pub patch_file_name: String
+}
+
 pub fn handle_hdiff(game_path: &str) {
     let game_path = PathBuf::from(game_path);
     if !game_path.exists() {
@@ -39,35 +84,66 @@ pub fn handle_hdiff(game_path: &str) {
         return;
     }
 
-    let mut hdiff_type = crate::utils::detect_hdiff_update_type(&game_path);
-    if hdiff_type == HdiffUpdateMode::None {
+    apply_hdiff_package(&game_path, &game_path);
+}
+
+pub fn handle_hdiff_out_of_place(source_path: &str, output_path: &str) {
+    let Some((source_path, output_path)) = crate::utils::prepare_output_path(source_path, output_path) else {
+        return;
+    };
+
+    apply_hdiff_package(&source_path, &output_path);
+}
+
+fn apply_hdiff_package(source_path: &Path, game_path: &Path) {
+    let game_path = game_path.to_path_buf();
+
+    let mut versions = None;
+    if crate::utils::detect_hdiff_update_type(&game_path) == HdiffUpdateMode::None {
         let hdiff_path = common::input::read_input("Please enter hdiff archive location: ");
         let hdiff_path = PathBuf::from(hdiff_path);
-        if !hdiff_path.exists() {
+        if !common::archive::exists(&hdiff_path) {
             eprintln!("Could not find file {}", hdiff_path.display());
             return;
         }
 
-        let sevenzip = match SevenZip::instance() {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to fetch SevenZip: {}", e);
+        versions = common::archive::base_path(&hdiff_path)
+            .file_name()
+            .and_then(|name| common::version::versions_from_file_name(&name.to_string_lossy()));
+        if let Some((source_version, target_version)) = versions {
+            println!("Patch updates version {} to {}", source_version, target_version);
+            if !crate::utils::check_version_applicable(source_path, source_version) {
                 return;
             }
-        };
+        }
 
-        println!("Extracting patch...");
-        if let Err(e) = sevenzip.extract_to(&hdiff_path, &game_path) {
-            eprintln!("Failed to extract hdiff: {}", e);
+        if !crate::utils::install_package(
+            &hdiff_path,
+            &game_path,
+            "hdiff",
+            |path| crate::utils::detect_hdiff_update_type(path) != HdiffUpdateMode::None,
+            "Hdiff package is wrongly built; please redownload and unpack it manually.",
+        ) {
             return;
         }
+    } else if !crate::options::signature::check_package(&game_path, false) {
+        return;
+    }
 
-        hdiff_type = crate::utils::detect_hdiff_update_type(&game_path);
-        if hdiff_type == HdiffUpdateMode::None {
-            eprintln!("Hdiff package is wrongly built; please redownload and unpack it manually.");
-            return;
+    let mut target_version = versions.map(|(_, target_version)| target_version);
+    match PackageInfo::read(&game_path) {
+        Ok(Some(info)) => {
+            info.print();
+            if !info.check(source_path, versions.map(|(source_version, _)| source_version)) {
+                return;
+            }
+            target_version = info.target_version().or(target_version);
         }
+        Ok(None) => {}
+        Err(e) => eprintln!("Failed to read {}: {}", PACKAGE_INFO_NAME, e),
     }
 
-    HdiffHandler::new(&game_path).apply();
+    if HdiffHandler::with_source(&game_path, source_path).apply() {
+        crate::utils::update_config_version(&game_path, target_version);
+    }
 }
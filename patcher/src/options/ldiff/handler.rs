@@ -1,31 +1,128 @@
-use std::{collections::HashSet, error::Error, fs::{remove_file, File}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use std::{collections::{BTreeSet, HashMap, HashSet}, error::Error, fs::{remove_file, File}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
 
-use common::{embedded::{HPatchz, SevenZip}, utils};
+use common::{embedded::{HPatchz, SevenZip}, safe_path::safe_join, utils, version::GameVersion};
 use indicatif::ProgressBar;
 use sophon::{modules::{Manifest, SophonParser}, sophon_patch::{SophonPatchAssetChunk, SophonPatchAssetInfo, SophonPatchAssetProperty, SophonPatchProto}};
+use crate::options::package_info::PACKAGE_INFO_NAME;
+
+/// The manifest patchmaker writes into ldiff packages.
+const LDIFF_MANIFEST_NAME: &str = "ldiff_manifest~";
 
 
 pub struct LdiffHandler<'a> {
-    pub game_path: &'a Path
+    pub game_path: &'a Path,
+    pub source_path: &'a Path,
 }
 
 impl<'a> LdiffHandler<'a> {
     pub fn new(game_path: &'a Path) -> Self {
-        Self { game_path }
+        Self { game_path, source_path: game_path }
+    }
+    
+    pub fn with_source(game_path: &'a Path, source_path: &'a Path) -> Self {
+        Self { game_path, source_path }
+    }
+    
+    fn is_out_of_place(&self) -> bool {
+        self.source_path != self.game_path
     }
     
-    pub fn apply(&self) {
+    pub fn apply(&self) -> bool {
         let Some(manifest_proto) = self.get_manifest_proto() else {
             eprintln!("Failed to parse manifest proto");
-            return;
+            return false;
         };
         
         println!("Item Count: {}", manifest_proto.patch_assets.len());
-        self.process_patch_assets(&manifest_proto);
+        let Some(version_tag) = self.select_version_tag(&manifest_proto) else {
+            return false;
+        };
+        let success = self.apply_version_tag(&manifest_proto, &version_tag);
+        if success {
+            crate::utils::update_config_version(self.game_path, None);
+        }
+        success
+    }
+    
+    /// Patches from a known version tag and cleans up the package, leaving config.ini alone.
+    /// Returns whether every file was patched.
+    pub fn apply_version_tag(&self, manifest_proto: &SophonPatchProto, version_tag: &str) -> bool {
+        let success = self.process_patch_assets(manifest_proto, version_tag);
         self.clean();
+        success
+    }
+    
+    fn select_version_tag(&self, manifest_proto: &SophonPatchProto) -> Option<String> {
+        let version_tags: BTreeSet<_> = manifest_proto
+            .patch_assets
+            .iter()
+            .flat_map(|asset| &asset.asset_infos)
+            .map(|info| info.version_tag.clone())
+            .collect();
+        
+        if let Some(version) = common::version::detect_version(self.source_path) {
+            println!("Detected installed version: {}", version);
+            let matching_tag = version_tags
+                .iter()
+                .find(|tag| tag.parse::<GameVersion>().ok() == Some(version));
+            if let Some(tag) = matching_tag {
+                return Some(tag.clone());
+            }
+            eprintln!("This patch does not support version {} (supported: {})", version, version_tags.iter().cloned().collect::<Vec<_>>().join(", "));
+        }
+        
+        if let Some(tag) = self.fingerprint_version_tag(manifest_proto) {
+            println!("Install files match version tag {}", tag);
+            return Some(tag);
+        }
+        
+        eprintln!("Could not determine which version this install is.");
+        if !common::input::confirm("Select a version tag manually?") {
+            return None;
+        }
+        println!("Available version tags:");
+        for tag in &version_tags { println!("- {}", tag); }
+        let version_tag = common::input::read_input("Select version tag: ");
+        version_tags.contains(&version_tag).then_some(version_tag)
+    }
+    
+    fn fingerprint_version_tag(&self, manifest_proto: &SophonPatchProto) -> Option<String> {
+        const SAMPLES_PER_TAG: usize = 8;
+        
+        let mut originals: HashMap<&str, Vec<&SophonPatchAssetChunk>> = HashMap::new();
+        for info in manifest_proto.patch_assets.iter().flat_map(|asset| &asset.asset_infos) {
+            if let Some(chunk) = info.chunk.as_ref().filter(|c| !c.original_file_name.is_empty()) {
+                originals.entry(&info.version_tag).or_default().push(chunk);
+            }
+        }
+        
+        let mut tag_counts: HashMap<(&str, &str), usize> = HashMap::new();
+        for chunk in originals.values().flatten() {
+            *tag_counts.entry((&chunk.original_file_name, &chunk.original_file_md5)).or_default() += 1;
+        }
+        
+        let mut matching_tags = originals.iter().filter(|(_, chunks)| {
+            let samples: Vec<_> = chunks
+                .iter()
+                .filter(|c| tag_counts[&(c.original_file_name.as_str(), c.original_file_md5.as_str())] == 1)
+                .take(SAMPLES_PER_TAG)
+                .collect();
+            !samples.is_empty() && samples.iter().all(|chunk| {
+                let Ok(path) = safe_join(self.source_path, &chunk.original_file_name) else {
+                    return false;
+                };
+                let size_matches = path.metadata().map(|m| m.len() == chunk.original_file_length as u64).unwrap_or(false);
+                size_matches && common::md5::calculate_md5(&path).map(|md5| md5 == chunk.original_file_md5).unwrap_or(false)
+            })
+        });
+        
+        match (matching_tags.next(), matching_tags.next()) {
+            (Some((tag, _)), None) => Some(tag.to_string()),
+            _ => None,
+        }
     }
     
-    pub fn process_patch_assets(&self, manifest_proto: &SophonPatchProto) {
+    pub fn process_patch_assets(&self, manifest_proto: &SophonPatchProto, version_tag: &str) -> bool {
         let complex_assets = manifest_proto
             .patch_assets
             .iter()
@@ -36,14 +133,47 @@ impl<'a> LdiffHandler<'a> {
         println!("Patching {} files...", complex_assets_len);
         let pb = utils::create_progress_bar(complex_assets_len);
         
-        for asset in &complex_assets {
-            self.process_complex_asset(asset, &pb);
-        }
+        let failed = complex_assets
+            .iter()
+            .filter(|asset| !self.process_complex_asset(asset, version_tag, &pb))
+            .count();
         
+        let mut success = self.mirror_source(manifest_proto);
+        if failed > 0 {
+            // Files left to delete can be the originals of the failed patches, which a retry needs
+            eprintln!("Failed to patch {} of {} files, no files were deleted", failed, complex_assets_len);
+            return false;
+        }
         if let Err(e) = self.handle_delete_files(manifest_proto) {
             eprintln!("An error occured while deleting files: {}", e);
+            success = false;
+        }
+        println!("Done!");
+        success
+    }
+    
+    fn mirror_source(&self, manifest_proto: &SophonPatchProto) -> bool {
+        if !self.is_out_of_place() {
+            return true;
+        }
+        
+        let ldiff_asset_set: HashSet<&str> = manifest_proto
+            .patch_assets
+            .iter()
+            .map(|asset| asset.asset_name.as_str())
+            .collect();
+        
+        println!("Linking unchanged files...");
+        let result = common::utils::mirror_missing_files(self.source_path, self.game_path, |rel_path| {
+            rel_path.starts_with("StarRail_Data/")
+                && !rel_path.contains("/Persistent/")
+                && !ldiff_asset_set.contains(rel_path)
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to link unchanged files: {}", e);
+            return false;
         }
-        println!("Done!")
+        true
     }
     
     pub fn handle_delete_files(&self, manifest_proto: &SophonPatchProto) -> Result<(), Box<dyn Error>> {
@@ -56,7 +186,7 @@ impl<'a> LdiffHandler<'a> {
         let star_rail_data_path = self.game_path.join("StarRail_Data");
         let all_game_files = self.walk_dir_excluding(&star_rail_data_path, "Persistent")?;
 
-        let files_to_delete: Vec<_> = all_game_files
+        let mut files_to_delete: Vec<_> = all_game_files
             .into_iter()
             .filter(|file_path| {
                 file_path
@@ -66,6 +196,17 @@ impl<'a> LdiffHandler<'a> {
             })
             .collect();
         
+        let unused_files: HashSet<PathBuf> = manifest_proto
+            .unused_assets
+            .iter()
+            .flat_map(|unused| &unused.asset_infos)
+            .flat_map(|info| &info.assets)
+            .filter(|file| !ldiff_asset_set.contains(&PathBuf::from(&file.file_name)))
+            .filter_map(|file| safe_join(self.game_path, &file.file_name).ok())
+            .filter(|path| path.is_file() && !files_to_delete.contains(path))
+            .collect();
+        files_to_delete.extend(unused_files);
+        
         let files_to_delete_len = files_to_delete.len();
         println!("Deleting {} files...", files_to_delete_len);
         let pb = utils::create_progress_bar(files_to_delete_len);
@@ -88,6 +229,13 @@ impl<'a> LdiffHandler<'a> {
         if let Some(manifest_path) = self.locate_manifest_file(){
             let _= std::fs::remove_file(&manifest_path);
         }
+
+        for name in [PACKAGE_INFO_NAME, common::signing::SIGNATURE_NAME] {
+            let path = self.game_path.join(name);
+            if path.exists() {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
     }
     
     fn walk_dir_excluding(
@@ -115,52 +263,59 @@ impl<'a> LdiffHandler<'a> {
         Ok(files)
     }
     
-    fn process_complex_asset(&self, asset: &SophonPatchAssetProperty, pb: &ProgressBar) {
-        let chosen_info = self.choose_asset_info(asset);
+    /// Returns false if the asset's patch failed.
+    fn process_complex_asset(&self, asset: &SophonPatchAssetProperty, version_tag: &str, pb: &ProgressBar) -> bool {
+        let Some(chosen_info) = self.choose_asset_info(asset, version_tag) else {
+            pb.inc(1);
+            return true;
+        };
+        let mut success = true;
         if let Some(chunk) = &chosen_info.chunk {
             if let Err(e) = self.apply_chunk(asset, chunk) {
                 pb.suspend(|| eprintln!("Failed to apply chunk for {}: {}", asset.asset_name, e));
+                success = false;
             }
             pb.inc(1);
         }
+        success
     }
     
-    fn choose_asset_info(&self, asset: &SophonPatchAssetProperty) -> SophonPatchAssetInfo {
-        if asset.asset_infos.len() == 1 {
-            asset.asset_infos[0].clone()
-        } else {
-            let version_tags: HashSet<_> = asset.asset_infos.iter().map(|c| c.version_tag.clone()).collect();
-            println!("Available version tags:");
-            for tag in &version_tags { println!("- {}", tag); }
-            let version_tag = common::input::read_input("Select version tag: ");
-            asset.asset_infos.iter()
-                .find(|info| info.version_tag == version_tag)
-                .unwrap_or(&asset.asset_infos[0]).clone()
-        }
+    fn choose_asset_info<'b>(&self, asset: &'b SophonPatchAssetProperty, version_tag: &str) -> Option<&'b SophonPatchAssetInfo> {
+        asset.asset_infos.iter().find(|info| info.version_tag == version_tag)
     }
     
     fn apply_chunk(&self, asset: &SophonPatchAssetProperty, chunk: &SophonPatchAssetChunk) -> Result<(), String> {
         let ldiff_path = self.game_path.join("ldiff");
-        let source_file = if chunk.original_file_name.is_empty() { PathBuf::new() } else { self.game_path.join(&chunk.original_file_name) };
+        let source_file = if chunk.original_file_name.is_empty() {
+            PathBuf::new()
+        } else {
+            safe_join(self.source_path, &chunk.original_file_name).map_err(|e| e.to_string())?
+        };
+        let patch_file = safe_join(&ldiff_path, &chunk.patch_name).map_err(|e| e.to_string())?;
+        let asset_path = safe_join(self.game_path, &asset.asset_name).map_err(|e| e.to_string())?;
         let temp_patch = ldiff_path.join("temp_patch_file");
         let temp_target = ldiff_path.join("temp_target_file");
     
-        write_patch_slice(&ldiff_path.join(&chunk.patch_name), chunk.patch_offset as u64, chunk.patch_length as u64, &temp_patch)
+        write_patch_slice(&patch_file, chunk.patch_offset as u64, chunk.patch_length as u64, &temp_patch)
             .map_err(|e| format!("Failed to write patch slice: {}", e))?;
     
-        HPatchz::instance().unwrap().patch(&source_file, &temp_patch, &temp_target)
-            .map_err(|e| format!("Patch failed: {}", e))?;
-    
-        let md5 = common::md5::calculate_md5(&temp_target).map_err(|e| format!("Failed to read patched file: {}", e))?;
-    
-        let asset_path = self.game_path.join(&asset.asset_name);
-        if md5 == asset.asset_hash_md5 {
-            std::fs::rename(&temp_target, &asset_path).map_err(|e| format!("Failed to replace asset: {}", e))?;
-        } else {
-            eprintln!("MD5 mismatch after patching {} (expected {}, got {})", asset.asset_name, asset.asset_hash_md5, md5);
-        }
+        let hpatchz = HPatchz::instance().map_err(|e| format!("Failed to get HPatchz instance: {}", e))?;
+        let result = hpatchz
+            .patch_to(&source_file, &temp_patch, &temp_target)
+            .map_err(|e| format!("Patch failed: {}", e))
+            .and_then(|_| common::md5::calculate_md5(&temp_target).map_err(|e| format!("Failed to read patched file: {}", e)))
+            .and_then(|md5| {
+                if md5 != asset.asset_hash_md5 {
+                    return Err(format!("MD5 mismatch after patching (expected {}, got {})", asset.asset_hash_md5, md5));
+                }
+                if let Some(parent) = asset_path.parent() {
+                    let _ = std::fs::create_dir_all(parent);
+                }
+                std::fs::rename(&temp_target, &asset_path).map_err(|e| format!("Failed to replace asset: {}", e))
+            });
     
-        if asset_path != source_file {
+        // The original is only consumed once its replacement is in place, so a failed patch can be retried
+        if result.is_ok() && asset_path != source_file && !self.is_out_of_place() {
             let _ = remove_file(&source_file);
         }
         
@@ -168,7 +323,7 @@ impl<'a> LdiffHandler<'a> {
         if temp_target.exists() {
             let _ = remove_file(&temp_target);
         }
-        Ok(())
+        result
     }
     
     pub fn get_manifest_proto(&self) -> Option<SophonPatchProto> {
@@ -208,7 +363,11 @@ impl<'a> LdiffHandler<'a> {
         Some(manifest_proto)
     }
     
+    /// A signed package names its manifest exactly, so an unsigned look-alike can't stand in for it.
     fn locate_manifest_file(&self) -> Option<PathBuf> {
+        if self.game_path.join(common::signing::SIGNATURE_NAME).is_file() {
+            return Some(self.game_path.join(LDIFF_MANIFEST_NAME)).filter(|path| path.is_file());
+        }
         match std::fs::read_dir(&self.game_path) {
             Ok(entries) => entries
                 .filter_map(Result::ok)
@@ -1,5 +1,4 @@
-use std::path::PathBuf;
-use sophon::SevenZip;
+use std::path::{Path, PathBuf};
 
 pub mod handler;
 
@@ -13,32 +12,40 @@ pub fn handle_ldiff(game_path: &str) {
         return;
     }
     
+    apply_ldiff_package(&game_path, &game_path);
+}
+
+pub fn handle_ldiff_out_of_place(source_path: &str, output_path: &str) {
+    let Some((source_path, output_path)) = utils::prepare_output_path(source_path, output_path) else {
+        return;
+    };
+    
+    apply_ldiff_package(&source_path, &output_path);
+}
+
+fn apply_ldiff_package(source_path: &Path, game_path: &Path) {
+    let game_path = game_path.to_path_buf();
+    
     if !utils::ldiff_is_unpacked(&game_path) {
         let ldiff_path = common::input::read_input("Please enter ldiff archive location: ");
         let ldiff_path = PathBuf::from(ldiff_path);
-        if !ldiff_path.exists() {
+        if !common::archive::exists(&ldiff_path) {
             eprintln!("Could not find file {}", ldiff_path.display());
             return;
         }
 
-        let sevenzip = match SevenZip::instance() {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to fetch SevenZip: {}", e);
-                return;
-            }
-        };
-
-        println!("Extracting patch...");
-        if let Err(e) = sevenzip.extract_to(&ldiff_path, &game_path) {
-            eprintln!("Failed to extract ldiff: {}", e);
-            return;
-        }
-        if !utils::ldiff_is_unpacked(&game_path) {
-            eprintln!("Ldiff is damaged, redownload and unzip manually and try again.");
+        if !utils::install_package(
+            &ldiff_path,
+            &game_path,
+            "ldiff",
+            utils::ldiff_is_unpacked,
+            "Ldiff is damaged, redownload and unzip manually and try again.",
+        ) {
             return;
         }
+    } else if !crate::options::signature::check_package(&game_path, false) {
+        return;
     }
-    
-    LdiffHandler::new(&game_path).apply();
+
+    LdiffHandler::with_source(&game_path, source_path).apply();
 }
\ No newline at end of file
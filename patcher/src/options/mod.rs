@@ -1,3 +1,6 @@
 pub mod hdiff;
 pub mod ldiff;
+pub mod package_info;
+pub mod pkg_version;
+pub mod signature;
 pub mod verify;
\ No newline at end of file
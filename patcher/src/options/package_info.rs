@@ -0,0 +1,89 @@
+use std::{fs, path::Path};
+
+use common::version::GameVersion;
+use serde::Deserialize;
+
+pub const PACKAGE_INFO_NAME: &str = "package_info.json";
+
+/// The description patchmaker writes into every package.
+#[derive(Deserialize)]
+pub struct PackageInfo {
+    pub format: String,
+    pub game: Option<String>,
+    #[serde(default)]
+    pub source_versions: Vec<String>,
+    pub target_version: Option<String>,
+    pub created_at: String,
+    pub tool_version: String,
+    pub package_size: u64,
+    pub target_file_count: usize,
+    pub target_size: u64,
+}
+
+impl PackageInfo {
+    pub fn read(package_dir: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let path = package_dir.join(PACKAGE_INFO_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json_data = fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&json_data)?))
+    }
+
+    pub fn print(&self) {
+        let sources = if self.source_versions.is_empty() { "unknown".to_string() } else { self.source_versions.join(", ") };
+        println!(
+            "Package: {} for {}, {} -> {}",
+            self.format,
+            self.game.as_deref().unwrap_or("an unknown game"),
+            sources,
+            self.target_version.as_deref().unwrap_or("unknown"),
+        );
+        println!(
+            "Built {} by patchmaker {}: {:.1} MiB, producing {} files ({:.1} GiB)",
+            self.created_at,
+            self.tool_version,
+            self.package_size as f64 / 1024.0 / 1024.0,
+            self.target_file_count,
+            self.target_size as f64 / 1024.0 / 1024.0 / 1024.0,
+        );
+    }
+
+    pub fn target_version(&self) -> Option<GameVersion> {
+        self.target_version.as_deref()?.parse().ok()
+    }
+
+    /// Checks the package against the install at `game_path`, asking before going on with a
+    /// mismatch. `confirmed` is a source version the user has already been asked about.
+    pub fn check(&self, game_path: &Path, confirmed: Option<GameVersion>) -> bool {
+        if let (Some(expected), Some(installed)) = (&self.game, common::version::detect_game(game_path))
+            && *expected != installed
+        {
+            eprintln!("This package is for {}, but the install is {}", expected, installed);
+            if !common::input::confirm("Apply it anyway?") {
+                return false;
+            }
+        }
+
+        let source_versions: Vec<GameVersion> = self.source_versions.iter().filter_map(|version| version.parse().ok()).collect();
+        match source_versions.as_slice() {
+            [] => true,
+            [source_version] if confirmed == Some(*source_version) => true,
+            [source_version] => crate::utils::check_version_applicable(game_path, *source_version),
+            _ => match common::version::detect_version(game_path) {
+                Some(installed) if source_versions.contains(&installed) => {
+                    println!("Detected installed version: {}", installed);
+                    true
+                }
+                Some(installed) => {
+                    eprintln!("This package does not cover the installed version {}", installed);
+                    common::input::confirm("Apply it anyway?")
+                }
+                None => {
+                    eprintln!("Could not detect the installed version; cannot check that this package applies");
+                    common::input::confirm("Continue anyway?")
+                }
+            },
+        }
+    }
+}
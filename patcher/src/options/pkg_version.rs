@@ -0,0 +1,57 @@
+use std::path::{Path, PathBuf};
+
+use common::{embedded::SevenZip, pkg_version::{self, PkgVersionEntry}};
+use sophon::modules::{pkg_version_entries, Manifest, SophonParser};
+
+pub fn handle_pkg_version(game_path: &str) {
+    let game_path = PathBuf::from(game_path);
+    if !game_path.exists() {
+        eprintln!("Could not find folder {}", game_path.display());
+        return;
+    }
+
+    let manifest_path = common::input::read_input("Please enter Sophon manifest location (leave empty to scan the game folder): ");
+    let entries = if manifest_path.is_empty() {
+        println!("Scanning game folder...");
+        match pkg_version::scan_directory(&game_path) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Failed to scan {}: {}", game_path.display(), e);
+                return;
+            }
+        }
+    } else {
+        match read_manifest_entries(Path::new(&manifest_path)) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Failed to read manifest {}: {}", manifest_path, e);
+                return;
+            }
+        }
+    };
+
+    let pkg_version_path = game_path.join(pkg_version::PKG_VERSION_FILE);
+    if let Err(e) = pkg_version::write_pkg_version(&pkg_version_path, &entries) {
+        eprintln!("Failed to write {}: {}", pkg_version_path.display(), e);
+        return;
+    }
+    println!("Wrote {} entries to {}", entries.len(), pkg_version_path.display());
+}
+
+fn read_manifest_entries(manifest_path: &Path) -> Result<Vec<PkgVersionEntry>, Box<dyn std::error::Error>> {
+    let manifest_vec = if manifest_path.to_string_lossy().ends_with('~') {
+        std::fs::read(manifest_path)?
+    } else {
+        let extract_dir = PathBuf::from(common::utils::get_temp_files_path()?);
+        SevenZip::instance()?.extract_to(manifest_path, &extract_dir)?;
+        let extracted = extract_dir.join(format!("{}~", manifest_path.file_name().unwrap_or_default().display()));
+        let bytes = std::fs::read(&extracted)?;
+        let _ = std::fs::remove_file(&extracted);
+        bytes
+    };
+
+    match SophonParser::new().parse_manifest_file(manifest_vec)? {
+        Manifest::Full(proto) => Ok(pkg_version_entries(&proto)),
+        Manifest::Diff(_) => Err("patch manifests don't describe a full install".into()),
+    }
+}
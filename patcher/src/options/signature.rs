@@ -0,0 +1,90 @@
+use std::{path::{Path, PathBuf}, sync::OnceLock};
+
+use common::signing::{self, SigningError, VerifyingKey};
+
+static SIGNATURE_POLICY: OnceLock<SignaturePolicy> = OnceLock::new();
+
+const TRUSTED_KEYS_NAME: &str = "trusted_keys.txt";
+
+/// Which package signers are trusted, and whether anything else is refused outright.
+#[derive(Default)]
+pub struct SignaturePolicy {
+    trusted: Vec<VerifyingKey>,
+    strict: bool,
+}
+
+impl SignaturePolicy {
+    /// Reads `--trusted-keys=<file>` and `--require-signature`. Without the former, keys come
+    /// from `trusted_keys.txt` in the config folder when it exists.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut keys_path = None;
+        let mut strict = false;
+        for arg in args {
+            if let Some(path) = arg.strip_prefix("--trusted-keys=") {
+                keys_path = Some(PathBuf::from(path));
+            } else if arg == "--require-signature" {
+                strict = true;
+            }
+        }
+
+        let keys_path = keys_path.or_else(|| default_keys_path().filter(|path| path.is_file()));
+        let trusted = match keys_path {
+            Some(path) => signing::load_trusted_keys(&path).map_err(|e| e.to_string())?,
+            None => Vec::new(),
+        };
+        if strict && trusted.is_empty() {
+            return Err("--require-signature needs at least one trusted key, see --trusted-keys".to_string());
+        }
+        Ok(Self { trusted, strict })
+    }
+
+    /// Sets the policy used for every package; returns false if one was already in use.
+    pub fn configure(self) -> bool {
+        SIGNATURE_POLICY.set(self).is_ok()
+    }
+
+    fn current() -> &'static Self {
+        SIGNATURE_POLICY.get_or_init(|| Self::from_args(&[]).unwrap_or_default())
+    }
+}
+
+fn default_keys_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("hysilensdownloader").join(TRUSTED_KEYS_NAME))
+}
+
+/// Checks the signature of the package unpacked in `package_dir` and returns whether to go on.
+/// `staged` says the folder holds the package alone, so files it does not sign are refused too.
+pub fn check_package(package_dir: &Path, staged: bool) -> bool {
+    let policy = SignaturePolicy::current();
+    let error = match signing::verify_package(package_dir, &policy.trusted, staged) {
+        Ok(public_key) => {
+            println!("Package signed by trusted key {}", public_key);
+            if !staged {
+                println!("The package was unpacked into the game folder, so only the files it signs could be checked");
+            }
+            return true;
+        }
+        Err(e) => e,
+    };
+
+    if policy.strict {
+        eprintln!("Refusing package: {}", error);
+        return false;
+    }
+    match error {
+        SigningError::Unsigned => {
+            if !policy.trusted.is_empty() {
+                println!("Package is not signed");
+            }
+            true
+        }
+        SigningError::UntrustedKey(_) if policy.trusted.is_empty() => {
+            println!("Package is signed, but no trusted keys are configured to check it against");
+            true
+        }
+        error => {
+            eprintln!("Package signature check failed: {}", error);
+            common::input::confirm("Apply it anyway?")
+        }
+    }
+}
@@ -1,18 +1,9 @@
 use std::{io::{BufRead, BufReader}, path::Path};
 use rayon::prelude::*;
-use serde::Deserialize;
-
-#[derive(Deserialize)]
-pub struct FileEntry {
-    #[serde(rename = "remoteName")]
-    remote_name: String,
-    md5: String,
-    #[serde(rename = "fileSize")]
-    file_size: u64,
-}
+use common::pkg_version::PkgVersionEntry;
 
 pub fn verify_files(client_folder: &Path) -> std::io::Result<()> {
-    let pkg_version_path = client_folder.join("pkg_version");
+    let pkg_version_path = client_folder.join(common::pkg_version::PKG_VERSION_FILE);
     let file = std::fs::File::open(&pkg_version_path)?;
     let reader = BufReader::new(file);
 
@@ -20,7 +11,7 @@ pub fn verify_files(client_folder: &Path) -> std::io::Result<()> {
     let pb = common::utils::create_progress_bar(lines.len());
 
     let all_ok = lines.into_par_iter().enumerate().map(|(_, line)| {
-        let entry: FileEntry = match serde_json::from_str(&line) {
+        let entry: PkgVersionEntry = match serde_json::from_str(&line) {
             Ok(e) => e,
             Err(e) => {
                 pb.suspend(|| eprintln!("Failed to parse JSON: {}", e));
@@ -29,7 +20,14 @@ pub fn verify_files(client_folder: &Path) -> std::io::Result<()> {
             }
         };
 
-        let file_path = client_folder.join(&entry.remote_name);
+        let file_path = match common::safe_path::safe_join(client_folder, &entry.remote_name) {
+            Ok(path) => path,
+            Err(e) => {
+                pb.suspend(|| eprintln!("Invalid path {}: {}", entry.remote_name, e));
+                pb.inc(1);
+                return false;
+            }
+        };
         if !file_path.exists() {
             pb.suspend(|| eprintln!("Missing file: {}", file_path.display()));
             pb.inc(1);
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::{fs, io, path::{Path, PathBuf}};
+
+use common::version::GameVersion;
 
 #[derive(PartialEq)]
 pub enum HdiffUpdateMode {
@@ -45,4 +47,106 @@ pub fn manifest_exists(game_path: &PathBuf) -> bool {
             }),
         Err(_) => false,
     }
-}
\ No newline at end of file
+}
+
+pub fn check_version_applicable(game_path: &Path, source_version: GameVersion) -> bool {
+    let Some(installed) = common::version::detect_version(game_path) else {
+        eprintln!("Could not detect the installed version; cannot check that this patch applies");
+        return common::input::confirm("Continue anyway?");
+    };
+    
+    println!("Detected installed version: {}", installed);
+    if installed == source_version {
+        return true;
+    }
+    
+    eprintln!("This patch is for version {}, but the install is version {}", source_version, installed);
+    common::input::confirm("Apply it anyway?")
+}
+
+pub fn update_config_version(game_path: &Path, target_version: Option<GameVersion>) {
+    let Some(version) = target_version.or_else(|| common::version::read_binary_version(game_path)) else {
+        return;
+    };
+    
+    if common::version::read_config_version(game_path) == Some(version) {
+        return;
+    }
+    
+    match common::version::write_config_version(game_path, version) {
+        Ok(()) => println!("Updated {} to version {}", common::version::CONFIG_FILE, version),
+        Err(e) => eprintln!("Failed to update {}: {}", common::version::CONFIG_FILE, e),
+    }
+}
+
+pub fn prepare_output_path(source_path: &str, output_path: &str) -> Option<(PathBuf, PathBuf)> {
+    let source_path = PathBuf::from(source_path);
+    if !source_path.exists() {
+        eprintln!("Could not find folder {}", source_path.display());
+        return None;
+    }
+    
+    let output_path = PathBuf::from(output_path);
+    if let Err(e) = std::fs::create_dir_all(&output_path) {
+        eprintln!("Failed to create {}: {}", output_path.display(), e);
+        return None;
+    }
+    
+    let (Ok(source_canonical), Ok(output_canonical)) = (source_path.canonicalize(), output_path.canonicalize()) else {
+        eprintln!("Failed to resolve {} or {}", source_path.display(), output_path.display());
+        return None;
+    };
+    if output_canonical.starts_with(&source_canonical) || source_canonical.starts_with(&output_canonical) {
+        eprintln!("Output folder must not be inside the game folder or contain it");
+        return None;
+    }
+    
+    Some((source_path, output_path))
+}
+
+/// Folder inside the game folder that package archives are unpacked into before being checked.
+pub const PACKAGE_STAGING_NAME: &str = ".package_staging";
+
+/// Extracts the package archive into a staging folder, checks it there and only then moves it
+/// into `game_path`, so a package that fails its checks never touches the install.
+/// `is_unpacked` tells whether the staging folder holds a usable package.
+pub fn install_package(archive: &Path, game_path: &Path, kind: &str, is_unpacked: fn(&PathBuf) -> bool, damaged_message: &str) -> bool {
+    let staging = game_path.join(PACKAGE_STAGING_NAME);
+    let _ = fs::remove_dir_all(&staging);
+
+    println!("Extracting patch...");
+    let installed = if let Err(e) = common::archive::extract_verified(archive, &staging) {
+        eprintln!("Failed to extract {}: {}", kind, e);
+        false
+    } else if !is_unpacked(&staging) {
+        eprintln!("{}", damaged_message);
+        false
+    } else if !crate::options::signature::check_package(&staging, true) {
+        false
+    } else if let Err(e) = move_tree(&staging, game_path) {
+        eprintln!("Failed to move the {} into {}: {}", kind, game_path.display(), e);
+        false
+    } else {
+        true
+    };
+    let _ = fs::remove_dir_all(&staging);
+    installed
+}
+
+/// Moves every file under `from` to the same place under `to`, returning how many were moved.
+pub fn move_tree(from: &Path, to: &Path) -> io::Result<usize> {
+    let mut moved = 0;
+    for entry in walkdir::WalkDir::new(from).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(from).map_err(io::Error::other)?;
+        let target = to.join(rel_path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(entry.path(), &target)?;
+        moved += 1;
+    }
+    Ok(moved)
+}
@@ -1,11 +1,11 @@
 use serde::Serialize;
 use std::collections::{HashMap, HashSet};
-use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
-use std::io::Write;
 use std::sync::{Arc, Mutex};
 
-use common::embedded::HDiff;
+use rayon::prelude::*;
+
+use crate::{bundle::{self, BundleIndex}, diffcache::DiffCache, similarity::SimilarityIndex};
 
 #[derive(Debug, Serialize)]
 pub struct BlockPatchEntry {
@@ -20,10 +20,23 @@ pub struct BlockPatchEntry {
     pub patch_file_size: u64,
 }
 
+/// Lookups used to shortlist new blocks for an old one; bundle contents win over content similarity.
+struct CandidateIndex<'a> {
+    bundles: BundleIndex<usize>,
+    similarity: SimilarityIndex<'a, usize>,
+    old_assets: HashMap<&'a Path, Vec<String>>,
+}
+
+/// A new block diffed against an old one: its path and scan, and the patch's path and size.
+type Candidate<'a> = (String, &'a crate::scan::FileMeta, String, u64);
+
 pub fn generate_block_map(
     old_files: &HashMap<String, crate::scan::FileMeta>,
     new_files: &HashMap<String, crate::scan::FileMeta>,
     output_dir: &Path,
+    delete_list: &mut Vec<String>,
+    full_copies: &mut Vec<(String, crate::scan::FileMeta)>,
+    diffs: &DiffCache,
     use_faster_check: bool
 ) -> std::io::Result<Vec<BlockPatchEntry>> {
     let mut used_targets = HashSet::new();
@@ -49,14 +62,34 @@ pub fn generate_block_map(
         })
         .collect();
 
+    println!("Reading .block bundle directories...");
+    let new_assets: Vec<_> = filtered_new
+        .par_iter()
+        .map(|(_, new_meta)| bundle::read_asset_names(&new_meta.full_path).ok())
+        .collect();
+    let old_assets: HashMap<_, _> = old_blocks
+        .par_iter()
+        .filter_map(|(_, old_meta)| {
+            bundle::read_asset_names(&old_meta.full_path).ok().map(|names| (old_meta.full_path.as_path(), names))
+        })
+        .collect();
+    let unparsed = new_assets.iter().filter(|names| names.is_none()).count() + old_blocks.len() - old_assets.len();
+    if unparsed > 0 {
+        println!("{} .block files could not be parsed as bundles, falling back to similarity search for them", unparsed);
+    }
+
+    let mut index = CandidateIndex { bundles: BundleIndex::default(), similarity: SimilarityIndex::default(), old_assets };
+    for (position, ((_, new_meta), names)) in filtered_new.iter().zip(&new_assets).enumerate() {
+        if let Some(names) = names {
+            index.bundles.insert(position, names);
+        }
+        if let Some(signature) = &new_meta.signature {
+            index.similarity.insert(position, signature);
+        }
+    }
+
     println!("Patching .block files...");
     let pb = common::utils::create_progress_bar(old_blocks.len());
-    let delete_path = output_dir.join("deletefiles.txt");
-    let mut delete_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(delete_path)?;
-
     for (rel, old_meta) in old_blocks
     {
         if let Some(new_meta) = new_files.get(rel) {
@@ -66,8 +99,8 @@ pub fn generate_block_map(
                 continue;
             }
         }
-        let mut hdiff_to_delete = Vec::new();
-        match find_best_patch_candidate(old_meta, &filtered_new, &used_targets, output_dir, &mut hdiff_to_delete, use_faster_check) {
+        let (best_candidate, hdiff_to_delete) = find_best_patch_candidate(old_meta, &filtered_new, &index, &used_targets, output_dir, diffs, use_faster_check);
+        match best_candidate {
             Some((new_rel, new_meta, patch_rel, patch_file_size)) => {
                 if patch_file_size > new_meta.size {
                     let _ = std::fs::remove_file(output_dir.join(&rel));
@@ -75,8 +108,11 @@ pub fn generate_block_map(
                         let _ = std::fs::remove_file(bad_patch);
                     }
             
-                    std::fs::copy(&new_meta.full_path, output_dir.join(&new_rel))?;
+                    full_copies.push((new_rel.clone(), new_meta.clone()));
                     used_targets.insert(new_rel);
+                    if !new_files.contains_key(rel) {
+                        delete_list.push(rel.clone());
+                    }
                 } else {
                     let patch_md5 = common::md5::calculate_md5(&output_dir.join(&patch_rel))
                         .map_err(|e| std::io::Error::new(
@@ -103,7 +139,7 @@ pub fn generate_block_map(
                 }
             }
             None => {
-                writeln!(delete_file, "{}", rel)?;
+                delete_list.push(rel.clone());
             }
         }
         pb.inc(1);
@@ -111,17 +147,12 @@ pub fn generate_block_map(
     
     pb.finish();
 
-    for (new_rel, new_meta) in &filtered_new {
-        if used_targets.contains(*new_rel) {
-            continue;
-        }
-
-        let patch_path = output_dir.join(&new_rel);
-        if let Some(parent) = patch_path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-        std::fs::copy(&new_meta.full_path, &patch_path)?;
-    }
+    full_copies.extend(
+        filtered_new
+            .iter()
+            .filter(|(new_rel, _)| !used_targets.contains(*new_rel))
+            .map(|(new_rel, new_meta)| ((*new_rel).clone(), (*new_meta).clone())),
+    );
 
     Ok(result)
 }
@@ -129,45 +160,54 @@ pub fn generate_block_map(
 fn find_best_patch_candidate<'a>(
     old_meta: &'a crate::scan::FileMeta,
     filtered_new: &[(&'a String, &'a crate::scan::FileMeta)],
+    index: &CandidateIndex,
     used_targets: &HashSet<String>,
     output_dir: &Path,
-    hdiff_to_delete: &mut Vec<PathBuf>,
+    diffs: &DiffCache,
     use_faster_check: bool,
-) -> Option<(String, &'a crate::scan::FileMeta, String, u64)> {
+) -> (Option<Candidate<'a>>, Vec<PathBuf>) {
     let best_candidate;
+    let take_for_hdiff = if use_faster_check { 1 } else { 3 };
+
+    let shortlist = |matches: Vec<(usize, f64)>| -> Vec<_> {
+        matches
+            .into_iter()
+            .map(|(position, _)| &filtered_new[position])
+            .filter(|(new_rel, _)| !used_targets.contains(*new_rel))
+            .take(take_for_hdiff)
+            .collect()
+    };
+
+    let mut candidates = shortlist(
+        index.old_assets
+            .get(old_meta.full_path.as_path())
+            .map(|names| index.bundles.query(names))
+            .unwrap_or_default(),
+    );
+
+    if candidates.is_empty() {
+        candidates = shortlist(
+            old_meta.signature
+                .as_deref()
+                .map(|signature| index.similarity.query(signature))
+                .unwrap_or_default(),
+        );
+    }
 
-    let mut candidates: Vec<_> = filtered_new
-        .iter()
-        .filter(|(new_rel, _)| !used_targets.contains(*new_rel))
-        .collect();
-
-    candidates.sort_by_key(|(_, new_meta)| (new_meta.size as i64 - old_meta.size as i64).abs());
-
-    let (take_top_n, take_for_hdiff) = if use_faster_check { (15, 2) } else { (50, 5) };
-
-    let top_candidates: Vec<_> = candidates.iter().take(take_top_n).collect();
-    
-    use rayon::prelude::*;
-
-    let mut candidates_with_similarity: Vec<_> = top_candidates
-        .par_iter()
-        .map(|(new_rel, new_meta)| {
-            let similarity = crate::utils::block_diff_percent_experimental(
-                &old_meta.full_path,
-                &new_meta.full_path,
-            )
-            .unwrap_or(f64::MAX);
-            (*new_rel, *new_meta, similarity)
-        })
-        .collect();
-
-    candidates_with_similarity.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal));
+    // Blocks without a signature or without any indexed neighbour fall back to the closest sizes
+    if candidates.is_empty() {
+        candidates = filtered_new
+            .iter()
+            .filter(|(new_rel, _)| !used_targets.contains(*new_rel))
+            .collect();
+        candidates.sort_by_key(|(_, new_meta)| (new_meta.size as i64 - old_meta.size as i64).abs());
+        candidates.truncate(take_for_hdiff);
+    }
 
     let hdiff_collector = Arc::new(Mutex::new(Vec::new()));
-    let results: Vec<_> = candidates_with_similarity
+    let results: Vec<_> = candidates
         .into_par_iter()
-        .take(take_for_hdiff)
-        .filter_map(|(new_rel, new_meta, _)| {
+        .filter_map(|&(new_rel, new_meta)| {
             let size_diff = (new_meta.size as i64 - old_meta.size as i64).abs();
             if size_diff > 5_000_000 {
                 return None;
@@ -176,8 +216,7 @@ fn find_best_patch_candidate<'a>(
             let patch_rel = format!("{new_rel}.hdiff");
             let patch_path = output_dir.join(&patch_rel);
     
-            let hdiff = HDiff::instance().ok()?;
-            if hdiff.diff(&old_meta.full_path, &new_meta.full_path, &patch_path).is_err() {
+            if diffs.diff(new_rel, Some(old_meta), new_meta, &patch_path).is_err() {
                 return None;
             }
             hdiff_collector.lock().unwrap().push(patch_path.clone());
@@ -188,8 +227,8 @@ fn find_best_patch_candidate<'a>(
         })
         .collect();
     
-    hdiff_to_delete.extend(hdiff_collector.lock().unwrap().drain(..));
+    let hdiff_to_delete = hdiff_collector.lock().unwrap().drain(..).collect();
     best_candidate = results.into_iter().min_by_key(|(_, _, _, size)| *size);
 
-    best_candidate
+    (best_candidate, hdiff_to_delete)
 }
@@ -0,0 +1,343 @@
+use std::{collections::HashMap, fs::File, io::{self, BufReader, Read, Seek, SeekFrom}, path::Path};
+
+const UNITYFS_SIGNATURE: &[u8; 8] = b"UnityFS\0";
+const COMPRESSION_MASK: u32 = 0x3f;
+const BLOCKS_INFO_AT_END: u32 = 0x80;
+const MAX_BLOCKS_INFO_SIZE: u32 = 64 * 1024 * 1024;
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads the directory tables of every UnityFS bundle stored back to back in `path`
+/// and returns the sorted names of the contained assets.
+pub fn read_asset_names(path: &Path) -> io::Result<Vec<String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let file_len = reader.get_ref().metadata()?.len();
+
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while offset < file_len {
+        reader.seek(SeekFrom::Start(offset))?;
+        let bundle_size = read_bundle(&mut reader, offset, &mut names)?;
+        if bundle_size == 0 {
+            return Err(invalid_data("UnityFS bundle reports a zero size"));
+        }
+        offset = offset
+            .checked_add(bundle_size)
+            .filter(|end| *end <= file_len)
+            .ok_or_else(|| invalid_data("UnityFS bundle runs past the end of the file"))?;
+    }
+
+    if names.is_empty() {
+        return Err(invalid_data("No assets found in bundle"));
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+fn read_bundle<R: Read + Seek>(reader: &mut R, start: u64, names: &mut Vec<String>) -> io::Result<u64> {
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature)?;
+    if &signature != UNITYFS_SIGNATURE {
+        return Err(invalid_data("Not a UnityFS bundle"));
+    }
+
+    let format_version = read_u32(reader)?;
+    read_cstring(reader)?;
+    read_cstring(reader)?;
+    let bundle_size = read_u64(reader)?;
+    let compressed_size = read_u32(reader)?;
+    let uncompressed_size = read_u32(reader)?;
+    let flags = read_u32(reader)?;
+    if compressed_size > MAX_BLOCKS_INFO_SIZE || uncompressed_size > MAX_BLOCKS_INFO_SIZE {
+        return Err(invalid_data("UnityFS directory info is too large"));
+    }
+
+    let blocks_info_offset = if flags & BLOCKS_INFO_AT_END != 0 {
+        start
+            .checked_add(bundle_size)
+            .and_then(|end| end.checked_sub(compressed_size as u64))
+            .ok_or_else(|| invalid_data("UnityFS directory info lies outside the bundle"))?
+    } else {
+        let header_end = reader.stream_position()? - start;
+        if format_version >= 7 { start + header_end.next_multiple_of(16) } else { start + header_end }
+    };
+
+    reader.seek(SeekFrom::Start(blocks_info_offset))?;
+    let mut compressed = vec![0u8; compressed_size as usize];
+    reader.read_exact(&mut compressed)?;
+
+    let blocks_info = match flags & COMPRESSION_MASK {
+        0 => compressed,
+        2 | 3 => lz4_decompress(&compressed, uncompressed_size as usize)?,
+        other => return Err(invalid_data(format!("Unsupported UnityFS directory compression {}", other))),
+    };
+
+    let mut cursor = io::Cursor::new(blocks_info.as_slice());
+    cursor.seek(SeekFrom::Current(16))?;
+    let block_count = read_u32(&mut cursor)?;
+    cursor.seek(SeekFrom::Current(block_count as i64 * 10))?;
+    let node_count = read_u32(&mut cursor)?;
+    for _ in 0..node_count {
+        cursor.seek(SeekFrom::Current(20))?;
+        names.push(read_cstring(&mut cursor)?);
+    }
+
+    Ok(bundle_size)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_cstring<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        bytes.push(byte[0]);
+        if bytes.len() > 4096 {
+            return Err(invalid_data("Unterminated string in UnityFS header"));
+        }
+    }
+    String::from_utf8(bytes).map_err(|e| invalid_data(e.to_string()))
+}
+
+fn lz4_decompress(src: &[u8], expected_size: usize) -> io::Result<Vec<u8>> {
+    let truncated = || invalid_data("Truncated LZ4 block");
+    let mut out = Vec::with_capacity(expected_size);
+    let mut pos = 0;
+
+    let read_length = |pos: &mut usize, mut length: usize| -> io::Result<usize> {
+        if length == 15 {
+            loop {
+                let byte = *src.get(*pos).ok_or_else(truncated)?;
+                *pos += 1;
+                length += byte as usize;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    };
+
+    while pos < src.len() {
+        let token = src[pos];
+        pos += 1;
+
+        let literal_len = read_length(&mut pos, (token >> 4) as usize)?;
+        let literals = src.get(pos..pos + literal_len).ok_or_else(truncated)?;
+        out.extend_from_slice(literals);
+        pos += literal_len;
+        if pos == src.len() {
+            break;
+        }
+
+        let offset = src.get(pos..pos + 2).ok_or_else(truncated)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(invalid_data("Invalid LZ4 match offset"));
+        }
+
+        let match_len = read_length(&mut pos, (token & 0x0f) as usize)? + 4;
+        if out.len() + match_len > expected_size {
+            return Err(invalid_data("LZ4 block is larger than expected"));
+        }
+        let match_start = out.len() - offset;
+        for i in 0..match_len {
+            out.push(out[match_start + i]);
+        }
+    }
+
+    if out.len() != expected_size {
+        return Err(invalid_data("LZ4 block size mismatch"));
+    }
+    Ok(out)
+}
+
+/// Maps contained asset names to the bundles holding them.
+#[derive(Default)]
+pub struct BundleIndex<T> {
+    entries: Vec<(T, usize)>,
+    by_name: HashMap<String, Vec<usize>>,
+}
+
+impl<T: Copy> BundleIndex<T> {
+    pub fn insert(&mut self, item: T, names: &[String]) {
+        let id = self.entries.len();
+        for name in names {
+            self.by_name.entry(name.clone()).or_default().push(id);
+        }
+        self.entries.push((item, names.len()));
+    }
+
+    /// Returns every indexed item sharing an asset with `names`, ordered by the Jaccard overlap of their asset sets.
+    pub fn query(&self, names: &[String]) -> Vec<(T, f64)> {
+        let mut shared: HashMap<usize, usize> = HashMap::new();
+        for id in names.iter().filter_map(|name| self.by_name.get(name)).flatten() {
+            *shared.entry(*id).or_default() += 1;
+        }
+
+        let mut matches: Vec<_> = shared
+            .into_iter()
+            .map(|(id, count)| {
+                let (item, len) = self.entries[id];
+                (item, count as f64 / (names.len() + len - count) as f64)
+            })
+            .collect();
+        matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `data` as a single literal-only LZ4 sequence.
+    fn lz4_literals(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        if data.len() < 15 {
+            out.push((data.len() as u8) << 4);
+        } else {
+            out.push(0xf0);
+            let mut rest = data.len() - 15;
+            while rest >= 255 {
+                out.push(255);
+                rest -= 255;
+            }
+            out.push(rest as u8);
+        }
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// A format 6 UnityFS bundle holding `names`, its directory info LZ4 compressed or stored.
+    fn bundle(names: &[&str], compressed: bool) -> Vec<u8> {
+        let mut info = vec![0u8; 16];
+        info.extend_from_slice(&1u32.to_be_bytes());
+        info.extend_from_slice(&[0u8; 10]);
+        info.extend_from_slice(&(names.len() as u32).to_be_bytes());
+        for name in names {
+            info.extend_from_slice(&[0u8; 20]);
+            info.extend_from_slice(name.as_bytes());
+            info.push(0);
+        }
+        let stored = if compressed { lz4_literals(&info) } else { info.clone() };
+
+        let mut header = UNITYFS_SIGNATURE.to_vec();
+        header.extend_from_slice(&6u32.to_be_bytes());
+        header.extend_from_slice(b"5.x.x\0");
+        header.extend_from_slice(b"2019.4.34f1\0");
+        let bundle_size = header.len() + 8 + 12 + stored.len();
+        header.extend_from_slice(&(bundle_size as u64).to_be_bytes());
+        header.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+        header.extend_from_slice(&(info.len() as u32).to_be_bytes());
+        header.extend_from_slice(&(if compressed { 3u32 } else { 0 }).to_be_bytes());
+        header.extend_from_slice(&stored);
+        header
+    }
+
+    #[test]
+    fn lz4_copies_literals() {
+        assert_eq!(lz4_decompress(&lz4_literals(b"hello"), 5).unwrap(), b"hello");
+        let long: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        assert_eq!(lz4_decompress(&lz4_literals(&long), long.len()).unwrap(), long);
+    }
+
+    #[test]
+    fn lz4_copies_overlapping_matches() {
+        // "ab", then 8 bytes from 2 back, reading what the match itself writes
+        assert_eq!(lz4_decompress(&[0x24, b'a', b'b', 2, 0], 10).unwrap(), b"ababababab");
+        // One literal repeated through an extended match length of 15 + 3 + 4
+        assert_eq!(lz4_decompress(&[0x1f, b'x', 1, 0, 3], 23).unwrap(), vec![b'x'; 23]);
+        // A match followed by closing literals
+        assert_eq!(lz4_decompress(&[0x10, b'z', 1, 0, 0x20, b'o', b'k'], 7).unwrap(), b"zzzzzok");
+    }
+
+    #[test]
+    fn lz4_rejects_truncated_and_malformed_input() {
+        let cases: &[(&[u8], usize)] = &[
+            (&[0x50, b'h', b'i'], 5),
+            (&[0xf0], 15),
+            (&[0xf0, 255], 300),
+            (&[0x14, b'a', 1], 5),
+            (&[0x1f, b'a', 1, 0], 30),
+            (&[0x14, b'a', 0, 0], 5),
+            (&[0x14, b'a', 2, 0], 6),
+            (&[0x1f, b'a', 1, 0, 255, 255], 5),
+            (&[0x14, b'a', 1, 0], 3),
+            (&[0x50, b'h', b'e', b'l', b'l', b'o'], 6),
+        ];
+        for (src, expected_size) in cases {
+            assert!(lz4_decompress(src, *expected_size).is_err(), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn reads_asset_names() {
+        for compressed in [false, true] {
+            let bytes = bundle(&["CAB-b", "CAB-a"], compressed);
+            let mut names = Vec::new();
+            let size = read_bundle(&mut io::Cursor::new(&bytes), 0, &mut names).unwrap();
+            assert_eq!(size, bytes.len() as u64);
+            assert_eq!(names, ["CAB-b", "CAB-a"]);
+        }
+    }
+
+    #[test]
+    fn reads_bundles_stored_back_to_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.block");
+        let mut bytes = bundle(&["CAB-c", "CAB-a"], true);
+        bytes.extend(bundle(&["CAB-b", "CAB-a"], false));
+        std::fs::write(&path, bytes).unwrap();
+
+        assert_eq!(read_asset_names(&path).unwrap(), ["CAB-a", "CAB-b", "CAB-c"]);
+    }
+
+    #[test]
+    fn rejects_truncated_bundles() {
+        let bytes = bundle(&["CAB-a"], true);
+        for len in 0..bytes.len() {
+            assert!(read_bundle(&mut io::Cursor::new(&bytes[..len]), 0, &mut Vec::new()).is_err(), "{}", len);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.block");
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read_asset_names(&path).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_end() {
+        let mut bytes = bundle(&["CAB-a"], false);
+        let size_at = UNITYFS_SIGNATURE.len() + 4 + b"5.x.x\0".len() + b"2019.4.34f1\0".len();
+        bytes[size_at..size_at + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.block");
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(read_asset_names(&path).is_err());
+
+        // With the directory info at the end, its offset is worked out from the bundle size
+        let flags_at = size_at + 8 + 8;
+        bytes[flags_at..flags_at + 4].copy_from_slice(&BLOCKS_INFO_AT_END.to_be_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(read_asset_names(&path).is_err());
+    }
+}
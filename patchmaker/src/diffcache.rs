@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicUsize, Ordering}, Mutex},
+};
+
+use common::embedded::{DiffOptions, HDiff};
+use serde::{Deserialize, Serialize};
+
+use crate::{rules::Rules, scan::FileMeta, utils::MemoryBudget};
+
+const RECORDS_NAME: &str = "records.jsonl";
+
+/// One generated patch, appended to the record log as soon as the patch is in the cache.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    old_md5: String,
+    new_md5: String,
+    options: String,
+    patch_size: u64,
+}
+
+/// Keeps every patch patchmaker generates next to the output folder, keyed by the hashes of its
+/// inputs and the hdiffz options, so later runs only diff what actually changed. Fresh diffs
+/// share one memory budget.
+pub struct DiffCache<'a> {
+    dir: PathBuf,
+    rules: &'a Rules,
+    budget: MemoryBudget,
+    records: Mutex<HashMap<String, u64>>,
+    log: Mutex<File>,
+    reused: AtomicUsize,
+    generated: AtomicUsize,
+}
+
+impl<'a> DiffCache<'a> {
+    pub fn open(dir: &Path, rules: &'a Rules, memory_budget: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let records_path = dir.join(RECORDS_NAME);
+
+        let mut records = HashMap::new();
+        if records_path.exists() {
+            // A run that crashed can leave a torn last line, which is simply skipped
+            for line in BufReader::new(File::open(&records_path)?).lines() {
+                if let Ok(record) = serde_json::from_str::<Record>(&line?) {
+                    records.insert(record.key, record.patch_size);
+                }
+            }
+        }
+        records.retain(|key, patch_size| {
+            fs::metadata(dir.join(file_name(key))).map(|meta| meta.len() == *patch_size).unwrap_or(false)
+        });
+
+        let log = OpenOptions::new().create(true).append(true).open(&records_path)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            rules,
+            budget: MemoryBudget::new(memory_budget),
+            records: Mutex::new(records),
+            log: Mutex::new(log),
+            reused: AtomicUsize::new(0),
+            generated: AtomicUsize::new(0),
+        })
+    }
+
+    /// Writes the patch from `old` (or from nothing) to `new` at `patch_path`, reusing a cached
+    /// patch when there is one.
+    pub fn diff(&self, rel_path: &str, old: Option<&FileMeta>, new: &FileMeta, patch_path: &Path) -> Result<(), String> {
+        let options = self.rules.diff_options(rel_path);
+        let old_md5 = old.map(|meta| meta.md5.as_str()).unwrap_or_default();
+        let options_text = options.args().join(" ");
+        let key = format!("{}:{}:{}", old_md5, new.md5, options_text);
+        if self.fetch(&key, patch_path) {
+            return Ok(());
+        }
+
+        if let Some(parent) = patch_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        {
+            let old_size = old.map(|meta| meta.size).unwrap_or(0);
+            let _reservation = self.budget.reserve(options.estimated_memory(old_size, new.size));
+            let old_path = old.map(|meta| meta.full_path.as_path()).unwrap_or(Path::new(""));
+            HDiff::instance()
+                .and_then(|hdiff| hdiff.diff_with(old_path, &new.full_path, patch_path, options))
+                .map_err(|e| e.to_string())?;
+        }
+
+        self.store(Record { key, old_md5: old_md5.to_string(), new_md5: new.md5.clone(), options: options_text, patch_size: 0 }, patch_path);
+        Ok(())
+    }
+
+    /// Runs a directory diff, keyed by every file hash on both sides plus the ignore list.
+    pub fn diff_dir(
+        &self,
+        (old_dir, old_files): (&Path, &[(String, String)]),
+        (new_dir, new_files): (&Path, &[(String, String)]),
+        patch_path: &Path,
+        ignore: &[String],
+        options: &DiffOptions,
+    ) -> Result<(), String> {
+        let listing = |files: &[(String, String)]| {
+            let mut files: Vec<_> = files.iter().collect();
+            files.sort_unstable();
+            let listing: Vec<String> = files.iter().map(|(rel_path, md5)| format!("{}={}", rel_path, md5)).collect();
+            format!("{:x}", md5::compute(listing.join("\n")))
+        };
+        let old_md5 = listing(old_files);
+        let new_md5 = listing(new_files);
+        let options_text = format!("{} -g#{}", options.args().join(" "), ignore.join("#"));
+        let key = format!("dir:{}:{}:{}", old_md5, new_md5, options_text);
+        if self.fetch(&key, patch_path) {
+            return Ok(());
+        }
+
+        HDiff::instance()
+            .and_then(|hdiff| hdiff.diff_dir(old_dir, new_dir, patch_path, ignore, options))
+            .map_err(|e| e.to_string())?;
+        self.store(Record { key, old_md5, new_md5, options: options_text, patch_size: 0 }, patch_path);
+        Ok(())
+    }
+
+    fn fetch(&self, key: &str, patch_path: &Path) -> bool {
+        if !self.records.lock().unwrap().contains_key(key) {
+            return false;
+        }
+        let _ = fs::remove_file(patch_path);
+        if common::utils::link_or_copy(&self.dir.join(file_name(key)), patch_path).is_err() {
+            return false;
+        }
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Failing to cache a patch only costs a rediff next time, so errors are just reported.
+    fn store(&self, mut record: Record, patch_path: &Path) {
+        self.generated.fetch_add(1, Ordering::Relaxed);
+        let cached = self.dir.join(file_name(&record.key));
+        let _ = fs::remove_file(&cached);
+        let result = common::utils::link_or_copy(patch_path, &cached).and_then(|_| {
+            record.patch_size = fs::metadata(&cached)?.len();
+            let line = serde_json::to_string(&record)?;
+            writeln!(self.log.lock().unwrap(), "{}", line)
+        });
+        match result {
+            Ok(()) => {
+                self.records.lock().unwrap().insert(record.key, record.patch_size);
+            }
+            Err(e) => eprintln!("Failed to cache patch {}: {}", patch_path.display(), e),
+        }
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Reused {} cached diffs, generated {}",
+            self.reused.load(Ordering::Relaxed),
+            self.generated.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn file_name(key: &str) -> String {
+    format!("{:x}.hdiff", md5::compute(key))
+}
@@ -0,0 +1,276 @@
+use std::{collections::{BTreeSet, HashMap}, fs, path::Path};
+
+use common::pkg_version::PKG_VERSION_FILE;
+use serde::Serialize;
+
+use crate::{diffcache::DiffCache, rules::Rules, scan::FileMeta};
+
+pub const DIR_DIFF_NAME: &str = "dirdiff.json";
+pub const DIR_DIFF_PATCH_NAME: &str = "dirdiff.hdiff";
+
+#[derive(Serialize)]
+pub struct DirDiff {
+    pub subtree: String,
+    pub patch_file_name: String,
+}
+
+/// The files a directory diff covers: everything under `subtree` except .block files, which the
+/// block map pairs, files the rules skip, and pkg_version when the package generates its own.
+pub struct DirScope<'a> {
+    subtree: String,
+    rules: &'a Rules,
+    generated_pkg_version: bool,
+}
+
+impl<'a> DirScope<'a> {
+    /// `subtree` is relative to the client root; empty or `.` means the whole client.
+    pub fn new(subtree: &str, rules: &'a Rules, generated_pkg_version: bool) -> Self {
+        let subtree = subtree.replace("\\", "/").trim_matches(['.', '/']).to_string();
+        Self { subtree, rules, generated_pkg_version }
+    }
+
+    fn relative<'p>(&self, rel_path: &'p str) -> Option<&'p str> {
+        if self.subtree.is_empty() {
+            return Some(rel_path);
+        }
+        rel_path.strip_prefix(self.subtree.as_str())?.strip_prefix('/')
+    }
+
+    fn wants(&self, rel_path: &str) -> bool {
+        self.relative(rel_path).is_some()
+            && !rel_path.ends_with(".block")
+            && !self.rules.is_skipped(rel_path)
+            && !(self.generated_pkg_version && rel_path == PKG_VERSION_FILE)
+    }
+}
+
+/// Splits both scans into the files the directory diff will carry and the rest, and works out
+/// the hdiffz ignore list that leaves the rest out of it.
+pub struct DirPlan {
+    pub old_rest: HashMap<String, FileMeta>,
+    pub new_rest: HashMap<String, FileMeta>,
+    pub deleted: Vec<String>,
+    ignore: Vec<String>,
+    /// Relative path and md5 of every covered file, keying the diff cache.
+    old_covered: Vec<(String, String)>,
+    new_covered: Vec<(String, String)>,
+}
+
+impl DirPlan {
+    pub fn new(scope: &DirScope, old_files: &HashMap<String, FileMeta>, new_files: &HashMap<String, FileMeta>) -> Self {
+        let under_subtree: Vec<&str> = old_files
+            .keys()
+            .chain(new_files.keys())
+            .filter_map(|rel_path| scope.relative(rel_path))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let ignore = ignore_list(&under_subtree, |rel| {
+            let rel_path = if scope.subtree.is_empty() { rel.to_string() } else { format!("{}/{}", scope.subtree, rel) };
+            !scope.wants(&rel_path)
+        });
+
+        // hdiffz matches ignore paths against the end of every path, so a wanted file can be
+        // caught by accident; those are diffed one by one instead
+        let covered = |rel_path: &str| {
+            scope.wants(rel_path) && scope.relative(rel_path).is_some_and(|rel| !ignore.iter().any(|pattern| matches(pattern, rel)))
+        };
+
+        let rest = |files: &HashMap<String, FileMeta>| -> HashMap<String, FileMeta> {
+            files
+                .iter()
+                .filter(|(rel_path, _)| !covered(rel_path))
+                .map(|(rel_path, meta)| (rel_path.clone(), meta.clone()))
+                .collect()
+        };
+        let mut deleted: Vec<String> = old_files
+            .keys()
+            .filter(|rel_path| covered(rel_path) && !new_files.contains_key(*rel_path) && !scope.rules.never_delete(rel_path))
+            .cloned()
+            .collect();
+        deleted.sort();
+
+        let covered_list = |files: &HashMap<String, FileMeta>| -> Vec<(String, String)> {
+            files
+                .iter()
+                .filter(|(rel_path, _)| covered(rel_path))
+                .map(|(rel_path, meta)| (rel_path.clone(), meta.md5.clone()))
+                .collect()
+        };
+
+        Self {
+            old_rest: rest(old_files),
+            new_rest: rest(new_files),
+            deleted,
+            old_covered: covered_list(old_files),
+            new_covered: covered_list(new_files),
+            ignore,
+        }
+    }
+
+    /// Runs hdiffz over the subtree of both clients and writes the patch and its manifest to `output_dir`.
+    pub fn write(&self, scope: &DirScope, old_root: &Path, new_root: &Path, output_dir: &Path, diffs: &DiffCache) -> std::io::Result<()> {
+        let (old_dir, new_dir) = if scope.subtree.is_empty() {
+            (old_root.to_path_buf(), new_root.to_path_buf())
+        } else {
+            (old_root.join(&scope.subtree), new_root.join(&scope.subtree))
+        };
+
+        println!("Diffing {} as one directory ({} paths ignored)...", new_dir.display(), self.ignore.len());
+        let patch_path = output_dir.join(DIR_DIFF_PATCH_NAME);
+        let _ = fs::remove_file(&patch_path);
+        diffs
+            .diff_dir(
+                (&old_dir, &self.old_covered),
+                (&new_dir, &self.new_covered),
+                &patch_path,
+                &self.ignore,
+                scope.rules.diff_options(&scope.subtree),
+            )
+            .map_err(|e| std::io::Error::other(format!("Directory diff failed: {}", e)))?;
+
+        let manifest = DirDiff { subtree: scope.subtree.clone(), patch_file_name: DIR_DIFF_PATCH_NAME.to_string() };
+        fs::write(output_dir.join(DIR_DIFF_NAME), serde_json::to_string_pretty(&manifest)?)?;
+        Ok(())
+    }
+}
+
+/// Covers the ignored paths with as few entries as possible, using a whole directory
+/// (`dir/`) wherever nothing under it is wanted.
+fn ignore_list(paths: &[&str], ignored: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut dirs: HashMap<&str, (usize, usize)> = HashMap::new();
+    let flags: Vec<bool> = paths.iter().map(|rel| ignored(rel)).collect();
+    for (rel, &is_ignored) in paths.iter().zip(&flags) {
+        for (index, _) in rel.match_indices('/') {
+            let counts = dirs.entry(&rel[..index]).or_default();
+            counts.0 += 1;
+            counts.1 += is_ignored as usize;
+        }
+    }
+
+    let mut ignore = BTreeSet::new();
+    for (rel, _) in paths.iter().zip(&flags).filter(|(_, is_ignored)| **is_ignored) {
+        let whole_dir = rel
+            .match_indices('/')
+            .map(|(index, _)| &rel[..index])
+            .find(|dir| dirs.get(dir).is_some_and(|(total, ignored)| total == ignored));
+        match whole_dir {
+            Some(dir) => ignore.insert(format!("{}/", dir)),
+            None => ignore.insert(rel.to_string()),
+        };
+    }
+    ignore.into_iter().collect()
+}
+
+/// Whether hdiffz's ignore `pattern` catches `rel_path`: the pattern has to match whole trailing
+/// components of the path, or of one of its directories for a pattern ending in `/`.
+fn matches(pattern: &str, rel_path: &str) -> bool {
+    let ends_with = |path: &str, suffix: &str| path == suffix || path.ends_with(&format!("/{}", suffix));
+    match pattern.strip_suffix('/') {
+        Some(dir) => rel_path.match_indices('/').any(|(index, _)| ends_with(&rel_path[..index], dir)),
+        None => ends_with(rel_path, pattern),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Action;
+    use common::embedded::DiffOptions;
+
+    fn rules() -> Rules {
+        Rules::load(None, Action::Diff, &DiffOptions::default()).unwrap()
+    }
+
+    fn scan(paths: &[&str]) -> HashMap<String, FileMeta> {
+        paths
+            .iter()
+            .map(|path| {
+                let meta = FileMeta { full_path: path.into(), md5: format!("{:x}", md5::compute(path)), size: 1, mtime: 0, signature: None };
+                (path.to_string(), meta)
+            })
+            .collect()
+    }
+
+    fn sorted_keys(files: &HashMap<String, FileMeta>) -> Vec<&str> {
+        let mut keys: Vec<&str> = files.keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn file_patterns_match_whole_trailing_components() {
+        assert!(matches("a.txt", "a.txt"));
+        assert!(matches("a.txt", "sub/a.txt"));
+        assert!(matches("sub/a.txt", "x/sub/a.txt"));
+        assert!(!matches("a.txt", "ba.txt"));
+        assert!(!matches("a.txt", "sub/ba.txt"));
+        assert!(!matches("sub/a.txt", "xsub/a.txt"));
+        assert!(!matches("a.txt", "a.txt/b"));
+    }
+
+    #[test]
+    fn dir_patterns_match_any_containing_directory() {
+        assert!(matches("sub/", "sub/a.txt"));
+        assert!(matches("sub/", "x/sub/a.txt"));
+        assert!(matches("sub/", "sub/deep/a.txt"));
+        assert!(matches("x/sub/", "y/x/sub/a.txt"));
+        assert!(!matches("sub/", "sub"));
+        assert!(!matches("sub/", "xsub/a.txt"));
+        assert!(!matches("sub/", "a/sub.txt"));
+    }
+
+    #[test]
+    fn ignore_list_collapses_fully_ignored_directories() {
+        let paths = ["a.txt", "cache/1", "cache/deep/2", "mixed/keep", "mixed/skip", "top.log"];
+        let ignore = ignore_list(&paths, |rel| rel.starts_with("cache/") || rel.ends_with("skip") || rel.ends_with(".log"));
+        assert_eq!(ignore, ["cache/", "mixed/skip", "top.log"]);
+    }
+
+    #[test]
+    fn ignore_list_is_empty_when_everything_is_wanted() {
+        assert!(ignore_list(&["a", "b/c"], |_| false).is_empty());
+    }
+
+    #[test]
+    fn plan_diffs_files_caught_by_an_ignore_suffix_one_by_one() {
+        let rules = rules();
+        let scope = DirScope::new(".", &rules, true);
+        // The root pkg_version is left out, so "pkg_version" is ignored, which also catches the wanted one below it
+        let old_files = scan(&["pkg_version", "StarRail_Data/pkg_version", "StarRail_Data/a.txt", "StarRail_Data/gone.txt"]);
+        let new_files = scan(&["pkg_version", "StarRail_Data/pkg_version", "StarRail_Data/a.txt", "StarRail_Data/x.block"]);
+        let plan = DirPlan::new(&scope, &old_files, &new_files);
+
+        assert_eq!(plan.ignore, ["StarRail_Data/x.block", "pkg_version"]);
+        assert_eq!(sorted_keys(&plan.new_rest), ["StarRail_Data/pkg_version", "StarRail_Data/x.block", "pkg_version"]);
+        assert_eq!(sorted_keys(&plan.old_rest), ["StarRail_Data/pkg_version", "pkg_version"]);
+        assert_eq!(plan.deleted, ["StarRail_Data/gone.txt"]);
+        for (rel_path, _) in plan.old_covered.iter().chain(&plan.new_covered) {
+            assert!(!plan.ignore.iter().any(|pattern| matches(pattern, rel_path)), "{}", rel_path);
+        }
+    }
+
+    #[test]
+    fn plan_covers_pkg_version_unless_it_is_generated() {
+        let rules = rules();
+        let files = scan(&["pkg_version", "config.ini", "StarRail_Data/Persistent/p.txt"]);
+
+        let plan = DirPlan::new(&DirScope::new(".", &rules, false), &files, &files);
+        assert_eq!(sorted_keys(&plan.new_rest), ["StarRail_Data/Persistent/p.txt"]);
+        assert_eq!(plan.ignore, ["StarRail_Data/"]);
+
+        let plan = DirPlan::new(&DirScope::new(".", &rules, true), &files, &files);
+        assert_eq!(sorted_keys(&plan.new_rest), ["StarRail_Data/Persistent/p.txt", "pkg_version"]);
+    }
+
+    #[test]
+    fn plan_keeps_files_outside_the_subtree() {
+        let rules = rules();
+        let files = scan(&["config.ini", "StarRail_Data/a.txt", "StarRail_Data/sub/b.txt"]);
+        let plan = DirPlan::new(&DirScope::new("./StarRail_Data/", &rules, false), &files, &files);
+
+        assert_eq!(sorted_keys(&plan.new_rest), ["config.ini"]);
+        assert!(plan.ignore.is_empty());
+        assert_eq!(plan.new_covered.len(), 2);
+    }
+}
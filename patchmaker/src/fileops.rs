@@ -0,0 +1,136 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs, path::Path};
+use serde::Serialize;
+
+use crate::{block::BlockPatchEntry, diffcache::DiffCache, rules::Rules, scan::FileMeta};
+
+pub const FILE_OPS_NAME: &str = "fileops.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOpKind {
+    Copy,
+    Move,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileOp {
+    pub kind: FileOpKind,
+    pub source_file_name: String,
+    pub target_file_name: String,
+    pub file_md5: String,
+    pub file_size: u64,
+}
+
+/// Finds added files whose exact content already exists in the old client and replaces their
+/// shipped copy or diff with a copy or move operation. Copies come first so a move never takes
+/// away a file another operation still reads from.
+pub fn detect_file_ops(
+    old_files: &HashMap<String, FileMeta>,
+    new_files: &HashMap<String, FileMeta>,
+    output_dir: &Path,
+    hdiff_entries: &mut Vec<BlockPatchEntry>,
+    full_copies: &mut Vec<(String, FileMeta)>,
+    rules: &Rules,
+) -> Vec<FileOp> {
+    let mut old_by_content: HashMap<(&str, u64), Vec<&String>> = HashMap::new();
+    for (rel_path, old_meta) in old_files.iter().filter(|(rel_path, _)| !rules.is_skipped(rel_path)) {
+        old_by_content.entry((old_meta.md5.as_str(), old_meta.size)).or_default().push(rel_path);
+    }
+    for sources in old_by_content.values_mut() {
+        sources.sort();
+    }
+
+    let mut targets: Vec<_> = new_files
+        .iter()
+        .filter(|(rel_path, _)| !old_files.contains_key(*rel_path) && !rules.is_skipped(rel_path))
+        .filter_map(|(rel_path, new_meta)| {
+            old_by_content.get(&(new_meta.md5.as_str(), new_meta.size)).map(|sources| (rel_path, new_meta, sources))
+        })
+        .collect();
+    targets.sort_by(|a, b| a.0.cmp(b.0));
+
+    let target_names: HashSet<&str> = targets.iter().map(|(rel_path, _, _)| rel_path.as_str()).collect();
+    hdiff_entries.retain(|entry| {
+        if !target_names.contains(entry.target_file_name.as_str()) {
+            return true;
+        }
+        let _ = fs::remove_file(output_dir.join(&entry.patch_file_name));
+        false
+    });
+    full_copies.retain(|(rel_path, _)| !target_names.contains(rel_path.as_str()));
+    let diff_sources: HashSet<&str> = hdiff_entries.iter().map(|entry| entry.source_file_name.as_str()).collect();
+
+    let mut claims: BTreeMap<&String, Vec<(&String, &FileMeta)>> = BTreeMap::new();
+    for (rel_path, new_meta, sources) in targets {
+        // Prefer sources that disappear in the new client, so the op can be a plain rename
+        let source = sources
+            .iter()
+            .find(|source| !new_files.contains_key(**source) && !claims.contains_key(**source))
+            .or_else(|| sources.iter().find(|source| !new_files.contains_key(**source)))
+            .unwrap_or(&sources[0]);
+        claims.entry(source).or_default().push((rel_path, new_meta));
+    }
+
+    let mut ops = Vec::new();
+    for (source, claimants) in claims {
+        let movable = !new_files.contains_key(source) && !diff_sources.contains(source.as_str());
+        let last = claimants.len() - 1;
+        for (index, (target, new_meta)) in claimants.into_iter().enumerate() {
+            ops.push(FileOp {
+                kind: if movable && index == last { FileOpKind::Move } else { FileOpKind::Copy },
+                source_file_name: source.clone(),
+                target_file_name: target.clone(),
+                file_md5: new_meta.md5.clone(),
+                file_size: new_meta.size,
+            });
+        }
+    }
+    ops.sort_by_key(|op| op.kind == FileOpKind::Move);
+
+    if !ops.is_empty() {
+        let moves = ops.iter().filter(|op| op.kind == FileOpKind::Move).count();
+        println!("Found {} renamed and {} duplicated files", moves, ops.len() - moves);
+    }
+    ops
+}
+
+/// Sophon patches have no copy or move, so ldiff packages diff each moved file against its identical
+/// original instead. Patching in place deletes that original, so duplicated files are left out here
+/// and shipped whole, since the new client still needs the file they were copied from.
+pub fn diff_from_originals(
+    ops: &[FileOp],
+    old_files: &HashMap<String, FileMeta>,
+    new_files: &HashMap<String, FileMeta>,
+    output_dir: &Path,
+    diffs: &DiffCache,
+) -> std::io::Result<Vec<BlockPatchEntry>> {
+    let mut entries = Vec::new();
+    for op in ops.iter().filter(|op| op.kind == FileOpKind::Move) {
+        let (Some(old_meta), Some(new_meta)) = (old_files.get(&op.source_file_name), new_files.get(&op.target_file_name)) else {
+            continue;
+        };
+
+        let patch_rel = format!("{}.hdiff", op.target_file_name);
+        let patch_path = output_dir.join(&patch_rel);
+        if let Some(parent) = patch_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        diffs
+            .diff(&op.target_file_name, Some(old_meta), new_meta, &patch_path)
+            .map_err(|e| std::io::Error::other(format!("hdiff failed for {}: {}", op.target_file_name, e)))?;
+
+        entries.push(BlockPatchEntry {
+            source_file_name: op.source_file_name.clone(),
+            source_file_md5: old_meta.md5.clone(),
+            source_file_size: old_meta.size,
+            target_file_name: op.target_file_name.clone(),
+            target_file_md5: new_meta.md5.clone(),
+            target_file_size: new_meta.size,
+            patch_file_md5: common::md5::calculate_md5(&patch_path)
+                .map_err(|e| std::io::Error::other(format!("MD5 error: {}", e)))?,
+            patch_file_size: fs::metadata(&patch_path)?.len(),
+            patch_file_name: patch_rel,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}};
+
+use prost::Message;
+use sophon::sophon_patch::{
+    SophonPatchAssetChunk, SophonPatchAssetInfo, SophonPatchAssetProperty, SophonPatchProto,
+    SophonUnusedAssetFile, SophonUnusedAssetInfo, SophonUnusedAssetProperty,
+};
+
+use crate::{block::BlockPatchEntry, diffcache::DiffCache, fileops::FileOp, rules::Rules, scan::FileMeta};
+
+pub const LDIFF_MANIFEST_NAME: &str = "ldiff_manifest~";
+pub const LDIFF_DIR: &str = "ldiff";
+const MAX_BLOB_SIZE: u64 = 512 * 1024 * 1024;
+
+struct BlobWriter {
+    dir: PathBuf,
+    blobs: Vec<PathBuf>,
+    file: Option<File>,
+    offset: u64,
+}
+
+impl BlobWriter {
+    fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self { dir: dir.to_path_buf(), blobs: Vec::new(), file: None, offset: 0 })
+    }
+
+    fn append(&mut self, patch_path: &Path) -> io::Result<(usize, u64, u64)> {
+        let length = fs::metadata(patch_path)?.len();
+        if self.file.is_none() || (self.offset > 0 && self.offset + length > MAX_BLOB_SIZE) {
+            let blob_path = self.dir.join(format!("blob_{}", self.blobs.len()));
+            self.file = Some(File::create(&blob_path)?);
+            self.blobs.push(blob_path);
+            self.offset = 0;
+        }
+
+        let file = self.file.as_mut().unwrap();
+        io::copy(&mut File::open(patch_path)?, file)?;
+        let offset = self.offset;
+        self.offset += length;
+        Ok((self.blobs.len() - 1, offset, length))
+    }
+
+    fn finish(mut self) -> io::Result<Vec<(String, u64)>> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+
+        let mut finished = Vec::new();
+        for blob_path in self.blobs {
+            let md5 = common::md5::calculate_md5(&blob_path)
+                .map_err(|e| io::Error::other(format!("MD5 error: {}", e)))?;
+            let size = fs::metadata(&blob_path)?.len();
+            fs::rename(&blob_path, self.dir.join(&md5))?;
+            finished.push((md5, size));
+        }
+        Ok(finished)
+    }
+}
+
+pub struct PatchSource {
+    pub version_tag: String,
+    pub old_client: PathBuf,
+    pub old_files: HashMap<String, FileMeta>,
+    pub work_dir: PathBuf,
+    pub hdiff_entries: Vec<BlockPatchEntry>,
+    /// Files shipped whole; ldiff diffs them from nothing instead.
+    pub full_copies: Vec<(String, FileMeta)>,
+    pub delete_list: Vec<String>,
+    pub file_ops: Vec<FileOp>,
+}
+
+pub fn write_ldiff_package(
+    new_files: &HashMap<String, FileMeta>,
+    output_dir: &Path,
+    sources: &[PatchSource],
+    rules: &Rules,
+    diffs: &DiffCache,
+) -> io::Result<()> {
+    let ldiff_dir = output_dir.join(LDIFF_DIR);
+    let temp_patch = output_dir.join("ldiff_temp_patch");
+    let mut blobs = BlobWriter::new(&ldiff_dir)?;
+    let mut slices: HashMap<String, (usize, u64, u64)> = HashMap::new();
+    let mut reused = 0;
+
+    let entries_by_target: Vec<HashMap<&str, &BlockPatchEntry>> = sources
+        .iter()
+        .map(|source| {
+            source.hdiff_entries
+                .iter()
+                .map(|entry| (entry.target_file_name.as_str(), entry))
+                .collect()
+        })
+        .collect();
+
+    let mut assets: Vec<_> = new_files
+        .iter()
+        .filter(|(rel_path, _)| !rules.is_skipped(rel_path))
+        .collect();
+    assets.sort_by(|a, b| a.0.cmp(b.0));
+
+    println!("Packing ldiff blobs...");
+    let pb = common::utils::create_progress_bar(assets.len());
+    let mut patch_assets = Vec::new();
+    let mut pending_chunks = Vec::new();
+
+    for (rel_path, new_meta) in assets {
+        let mut asset = SophonPatchAssetProperty {
+            asset_name: rel_path.clone(),
+            asset_size: new_meta.size as i64,
+            asset_hash_md5: new_meta.md5.clone(),
+            asset_infos: Vec::new(),
+        };
+
+        for (source, entries) in sources.iter().zip(&entries_by_target) {
+            let unchanged = source.old_files.get(rel_path).map(|old_meta| old_meta.md5 == new_meta.md5).unwrap_or(false);
+            if unchanged {
+                continue;
+            }
+
+            let (patch_path, original) = match entries.get(rel_path.as_str()) {
+                Some(entry) => (
+                    source.work_dir.join(&entry.patch_file_name),
+                    (entry.source_file_name.as_str(), entry.source_file_size, entry.source_file_md5.as_str()),
+                ),
+                None => (temp_patch.clone(), ("", 0, "")),
+            };
+
+            let slice_key = if patch_path == temp_patch {
+                format!("empty:{}", new_meta.md5)
+            } else {
+                common::md5::calculate_md5(&patch_path).map_err(|e| io::Error::other(format!("MD5 error: {}", e)))?
+            };
+
+            let (blob_index, offset, length) = match slices.get(&slice_key) {
+                Some(slice) => {
+                    reused += 1;
+                    *slice
+                }
+                None => {
+                    if patch_path == temp_patch {
+                        diffs
+                            .diff(rel_path, None, new_meta, &temp_patch)
+                            .map_err(|e| io::Error::other(format!("hdiff failed for {}: {}", rel_path, e)))?;
+                    }
+                    let slice = blobs.append(&patch_path)?;
+                    if patch_path == temp_patch {
+                        let _ = fs::remove_file(&temp_patch);
+                    }
+                    slices.insert(slice_key, slice);
+                    slice
+                }
+            };
+
+            let (original_file_name, original_file_length, original_file_md5) = original;
+            asset.asset_infos.push(SophonPatchAssetInfo {
+                version_tag: source.version_tag.clone(),
+                chunk: Some(patch_chunk(&source.version_tag, offset, length, original_file_name, original_file_length, original_file_md5)),
+            });
+            pending_chunks.push((patch_assets.len(), asset.asset_infos.len() - 1, blob_index));
+        }
+
+        patch_assets.push(asset);
+        pb.inc(1);
+    }
+    pb.finish();
+
+    let finished = blobs.finish()?;
+    for (asset_index, info_index, blob_index) in pending_chunks {
+        let (blob_md5, patch_size) = &finished[blob_index];
+        if let Some(chunk) = patch_assets[asset_index].asset_infos[info_index].chunk.as_mut() {
+            chunk.patch_name = blob_md5.clone();
+            chunk.patch_md5 = blob_md5.clone();
+            chunk.patch_size = *patch_size as i64;
+        }
+    }
+
+    let unused_assets = sources
+        .iter()
+        .map(|source| {
+            let unused_files = source.delete_list
+                .iter()
+                .filter_map(|rel_path| source.old_files.get(rel_path).map(|meta| (rel_path, meta)))
+                .map(|(rel_path, meta)| SophonUnusedAssetFile {
+                    file_name: rel_path.clone(),
+                    file_size: meta.size as i64,
+                    file_md5: meta.md5.clone(),
+                })
+                .collect();
+            SophonUnusedAssetProperty {
+                version_tag: source.version_tag.clone(),
+                asset_infos: vec![SophonUnusedAssetInfo { assets: unused_files }],
+            }
+        })
+        .collect();
+
+    let proto = SophonPatchProto { patch_assets, unused_assets };
+    fs::write(output_dir.join(LDIFF_MANIFEST_NAME), proto.encode_to_vec())?;
+
+    println!("Wrote {} blobs to {} ({} duplicate diffs reused)", finished.len(), ldiff_dir.display(), reused);
+    Ok(())
+}
+
+pub fn remove_loose_files(output_dir: &Path, keep_files: &[&str]) -> io::Result<()> {
+    for entry in fs::read_dir(output_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if keep_files.contains(&name) || name == LDIFF_DIR || name == LDIFF_MANIFEST_NAME {
+            continue;
+        }
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn patch_chunk(
+    version_tag: &str,
+    offset: u64,
+    length: u64,
+    original_file_name: &str,
+    original_file_length: u64,
+    original_file_md5: &str,
+) -> SophonPatchAssetChunk {
+    SophonPatchAssetChunk {
+        patch_name: String::new(),
+        version_tag: version_tag.to_string(),
+        build_id: String::new(),
+        patch_size: 0,
+        patch_md5: String::new(),
+        patch_offset: offset as i64,
+        patch_length: length as i64,
+        original_file_name: original_file_name.to_string(),
+        original_file_length: original_file_length as i64,
+        original_file_md5: original_file_md5.to_string(),
+    }
+}
@@ -1,63 +1,390 @@
 #![feature(once_cell_try)]
 use std::{
-    collections::HashSet, fs, path::PathBuf, time::Instant
+    collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}, time::Instant
 };
 
-use walkdir::WalkDir;
+use common::{archive::ArchiveFormat, embedded::DiffOptions};
+use common::pkg_version::{is_excluded, write_pkg_version, PkgVersionEntry, PKG_VERSION_FILE};
 
-use crate::{block::BlockPatchEntry, patch::process_regular_files};
+use crate::{block::BlockPatchEntry, diffcache::DiffCache, patch::process_regular_files, rules::{Action, Rules}};
 
 mod block;
+mod bundle;
+mod diffcache;
+mod dirdiff;
+mod fileops;
+mod ldiff;
+mod metadata;
 mod patch;
+mod payload;
+mod rules;
 mod scan;
+mod selftest;
+mod similarity;
 mod utils;
 
+const DEFAULT_MEMORY_BUDGET_GIB: u64 = 8;
+
+#[derive(serde::Serialize)]
+struct HdiffMap<'a> {
+    diff_map: &'a [BlockPatchEntry],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    copy_map: &'a [payload::CopyEntry],
+}
+
+/// Everything needed to turn one client into another.
+struct ClientDiff {
+    hdiff_entries: Vec<BlockPatchEntry>,
+    full_copies: Vec<(String, scan::FileMeta)>,
+    delete_list: Vec<String>,
+    file_ops: Vec<fileops::FileOp>,
+}
+
 #[derive(serde::Serialize)]
-struct HdiffMap {
-    diff_map: Vec<BlockPatchEntry>,
+struct FileOps<'a> {
+    operations: &'a [fileops::FileOp],
 }
 
 fn main() -> std::io::Result<()> {
     println!("Hysilens-Download Patchmaker made by Remi with love <3");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let self_test = args.iter().any(|arg| arg == "--self-test");
+    let diff_options = DiffOptions::from_args(&args).map_err(std::io::Error::other)?;
+    if let Some(key_path) = args.iter().find_map(|arg| arg.strip_prefix("--generate-key=")) {
+        let key_path = Path::new(key_path);
+        let public_key = common::signing::generate_key(key_path).map_err(std::io::Error::other)?;
+        println!("Wrote signing key to {}", key_path.display());
+        println!("Public key {} written to {}", public_key, common::signing::public_key_path(key_path).display());
+        return Ok(());
+    }
 
     let old_client_path = PathBuf::from(common::input::read_input("Please enter old client path: "));
     let new_client_path = PathBuf::from(common::input::read_input("Please enter new client path: "));
     let output_dir = PathBuf::from(common::input::read_input("Please enter hdiff output path: "));
     let hdiff_every_file = common::input::confirm("Apply HDiff to every file?");
+    let rules_path = common::input::read_input("Please enter rules file path (leave empty for defaults): ");
+    let fallback = if hdiff_every_file { Action::Diff } else { Action::Copy };
+    let rules = Rules::load((!rules_path.is_empty()).then(|| Path::new(&rules_path)), fallback, &diff_options)
+        .map_err(|e| std::io::Error::other(format!("Rules error: {}", e)))?;
+    let memory_budget = common::input::read_input(&format!("Memory budget for parallel diffs in GiB (default {}): ", DEFAULT_MEMORY_BUDGET_GIB))
+        .parse::<u64>()
+        .ok()
+        .filter(|gib| *gib > 0)
+        .unwrap_or(DEFAULT_MEMORY_BUDGET_GIB)
+        * 1024 * 1024 * 1024;
     let use_faster_check = common::input::confirm("Use faster block check?");
+    let generate_pkg_version = common::input::confirm("Generate pkg_version for the new client?");
+    let emit_ldiff = common::input::confirm("Package as Sophon ldiff instead of hdiffmap?");
+    let dir_subtree = if emit_ldiff {
+        None
+    } else {
+        let subtree = common::input::read_input("Directory-diff subtree, e.g. StarRail_Data or . for everything (leave empty to diff file by file): ");
+        (!subtree.is_empty()).then_some(subtree)
+    };
+    let dir_scope = dir_subtree.as_deref().map(|subtree| dirdiff::DirScope::new(subtree, &rules, generate_pkg_version));
+    let emit_rollback = common::input::confirm("Also generate a rollback package from the new client back to the old one?");
+    let archive_format = common::input::read_input("Archive the package as zip or 7z (leave empty to keep a folder): ");
+    let archive_format = (!archive_format.is_empty())
+        .then(|| archive_format.parse::<ArchiveFormat>())
+        .transpose()
+        .map_err(std::io::Error::other)?;
+    let volume_size = match archive_format {
+        Some(_) => {
+            let size = common::input::read_input("Split the archive into volumes of, e.g. 2g (leave empty for one file): ");
+            (!size.is_empty())
+                .then(|| common::utils::parse_size(&size).filter(|size| *size > 0).ok_or_else(|| format!("Invalid volume size: {:?}", size)))
+                .transpose()
+                .map_err(std::io::Error::other)?
+        }
+        None => None,
+    };
+    let signing_key = common::input::read_input("Signing key file (leave empty to leave the package unsigned): ");
+    let signing_key = (!signing_key.is_empty())
+        .then(|| common::signing::load_signing_key(Path::new(&signing_key)))
+        .transpose()
+        .map_err(std::io::Error::other)?;
+
+    let mut old_clients = vec![old_client_path];
+    if emit_ldiff {
+        loop {
+            let path = common::input::read_input("Please enter another old client path or scan cache (leave empty to finish): ");
+            if path.is_empty() {
+                break;
+            }
+            old_clients.push(PathBuf::from(path));
+        }
+    }
+
+    let mut version_tags = Vec::new();
+    for old_client in &old_clients {
+        let version_tag = if !emit_ldiff {
+            String::new()
+        } else if let Some(version) = common::version::detect_version(old_client) {
+            println!("Detected {} version: {}", old_client.display(), version);
+            version.to_string()
+        } else {
+            common::input::read_input(&format!("Please enter version tag for {}: ", old_client.display()))
+        };
+        if emit_ldiff && (version_tag.is_empty() || version_tag.contains(['/', '\\']) || version_tags.contains(&version_tag)) {
+            return Err(std::io::Error::other(format!("Invalid or duplicate version tag: {:?}", version_tag)));
+        }
+        version_tags.push(version_tag);
+    }
+
     let start = Instant::now(); 
     fs::create_dir_all(&output_dir)?;
-    
-    let keep_files: HashSet<_> = ["old_files.json", "new_files.json"].iter().collect();
+
+    let cache_names: Vec<String> = version_tags
+        .iter()
+        .map(|tag| if old_clients.len() == 1 { "old_files.json".to_string() } else { format!("old_files_{}.json", tag) })
+        .chain(std::iter::once("new_files.json".to_string()))
+        .collect();
+    let cache_refs: Vec<&str> = cache_names.iter().map(String::as_str).collect();
+    let keep_files: HashSet<_> = cache_refs.iter().collect();
     utils::clear_directory(&output_dir, keep_files)?;
-    let old_files = scan::load_or_scan(&old_client_path, &output_dir.join("old_files.json"))?;
-    let new_files = scan::load_or_scan(&new_client_path, &output_dir.join("new_files.json"))?;
+    let mut new_files = scan::load_or_scan(&new_client_path, &output_dir.join("new_files.json"))?;
+    // Lives outside the output folder, which is cleared on every run
+    let diffs = DiffCache::open(&utils::sibling_dir(&output_dir, "diffcache"), &rules, memory_budget)?;
 
-    let mut delete_list = Vec::new();
-    let mut hdiff_entries = Vec::new();
-    
-    process_regular_files(&old_files, &new_files, &output_dir, &mut delete_list, &mut hdiff_entries, hdiff_every_file)?;
+    let mut sources = Vec::new();
+    for ((old_client, version_tag), cache_name) in old_clients.iter().zip(version_tags).zip(&cache_names) {
+        let (old_client, old_files) = scan::load_source(old_client, &output_dir.join(cache_name))?;
+        let work_dir = if old_clients.len() == 1 { output_dir.clone() } else { output_dir.join(format!("from_{}", version_tag)) };
+        fs::create_dir_all(&work_dir)?;
+        if old_clients.len() > 1 {
+            println!("Diffing {} against the new client...", version_tag);
+        }
 
-    let block_entries = block::generate_block_map(&old_files, &new_files, &output_dir, use_faster_check)?;
-    hdiff_entries.extend(block_entries);
-    
-    let map_path = output_dir.join("hdiffmap.json");
-    let json_data = serde_json::to_string_pretty(&HdiffMap { diff_map: hdiff_entries })?;
-    fs::write(map_path, json_data)?;
+        let ClientDiff { mut hdiff_entries, full_copies, delete_list, mut file_ops } =
+            diff_clients((&old_client, &old_files), (&new_client_path, &new_files), &work_dir, &rules, &diffs, dir_scope.as_ref(), use_faster_check)?;
+        if emit_ldiff {
+            hdiff_entries.extend(fileops::diff_from_originals(&file_ops, &old_files, &new_files, &work_dir, &diffs)?);
+            file_ops.clear();
+        }
+
+        sources.push(ldiff::PatchSource { version_tag, old_client, old_files, work_dir, hdiff_entries, full_copies, delete_list, file_ops });
+    }
+
+    // Built before pkg_version is added, since the rollback reads from the new client as it is on disk
+    let rollback_dir = utils::sibling_dir(&output_dir, "rollback");
+    if emit_rollback {
+        println!("Diffing the new client back to {}...", sources[0].old_client.display());
+        fs::create_dir_all(&rollback_dir)?;
+        utils::clear_directory(&rollback_dir, HashSet::new())?;
+        let mut rollback =
+            diff_clients(
+                (&new_client_path, &new_files),
+                (&sources[0].old_client, &sources[0].old_files),
+                &rollback_dir,
+                &rules,
+                &diffs,
+                dir_scope.as_ref(),
+                use_faster_check,
+            )?;
+        if generate_pkg_version {
+            roll_back_pkg_version(&mut rollback, &rollback_dir, &sources[0].old_files);
+        }
+        write_hdiffmap(&rollback_dir, &rollback.hdiff_entries, &rollback.full_copies, &rollback.delete_list, &rollback.file_ops)?;
+        let source_versions = common::version::detect_version(&new_client_path).map(|version| version.to_string());
+        metadata::PackageInfo::new(
+            "hdiffmap",
+            source_versions.into_iter().collect(),
+            &sources[0].old_client,
+            &sources[0].old_files,
+            utils::folder_size(&rollback_dir, &[]),
+            &rules,
+        )
+        .write(&rollback_dir)?;
+        if let Some(key) = &signing_key {
+            sign_package(&rollback_dir, key, &[])?;
+        }
+    }
 
-    fs::write(output_dir.join("deletefiles.txt"), delete_list.join("\n"))?;
+    if generate_pkg_version {
+        let entries: Vec<_> = new_files
+            .iter()
+            .filter(|(rel_path, _)| !is_excluded(rel_path))
+            .map(|(rel_path, meta)| PkgVersionEntry {
+                remote_name: rel_path.clone(),
+                md5: meta.md5.clone(),
+                file_size: meta.size,
+            })
+            .collect();
+        let pkg_version_path = output_dir.join(PKG_VERSION_FILE);
+        write_pkg_version(&pkg_version_path, &entries)
+            .map_err(|e| std::io::Error::other(format!("pkg_version error: {}", e)))?;
+        for source in &mut sources {
+            drop_pkg_version_entries(&source.work_dir, &mut source.hdiff_entries, &mut source.full_copies, &mut source.file_ops, &mut source.delete_list);
+        }
+
+        let md5 = common::md5::calculate_md5(&pkg_version_path)
+            .map_err(|e| std::io::Error::other(format!("MD5 error: {}", e)))?;
+        let size = fs::metadata(&pkg_version_path)?.len();
+        new_files.insert(PKG_VERSION_FILE.to_string(), scan::FileMeta { full_path: pkg_version_path, md5, size, mtime: 0, signature: None });
+    }
+
+    if emit_ldiff {
+        ldiff::write_ldiff_package(&new_files, &output_dir, &sources, &rules, &diffs)?;
+        ldiff::remove_loose_files(&output_dir, &cache_refs)?;
+    } else {
+        let source = &sources[0];
+        write_hdiffmap(&output_dir, &source.hdiff_entries, &source.full_copies, &source.delete_list, &source.file_ops)?;
+    }
     
-    let folder_size: u64 = WalkDir::new(&output_dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter_map(|entry| fs::metadata(entry.path()).ok())
-        .map(|meta| meta.len())
-        .sum();
+    let source_versions = if emit_ldiff {
+        sources.iter().map(|source| source.version_tag.clone()).collect()
+    } else {
+        common::version::detect_version(&sources[0].old_client).map(|version| version.to_string()).into_iter().collect()
+    };
+    let format = if emit_ldiff { "ldiff" } else { "hdiffmap" };
+    let package_size = utils::folder_size(&output_dir, &cache_refs);
+    metadata::PackageInfo::new(format, source_versions, &new_client_path, &new_files, package_size, &rules).write(&output_dir)?;
+    if let Some(key) = &signing_key {
+        sign_package(&output_dir, key, &cache_refs)?;
+    }
+    let folder_size = utils::folder_size(&output_dir, &cache_refs);
     
     let elapsed = start.elapsed();
+    diffs.print_summary();
     println!("Patch folder prepared successfully!");
     println!("Total patch folder size: {:.1} MiB", folder_size as f64 / 1024.0 / 1024.0);
     println!("Total processing time: {:.2?}", elapsed);
+    if emit_rollback {
+        println!("Rollback package prepared in {}", rollback_dir.display());
+    }
+
+    if self_test {
+        for source in &sources {
+            let kind = if emit_ldiff {
+                selftest::PackageKind::Ldiff { version_tag: &source.version_tag }
+            } else {
+                selftest::PackageKind::Hdiff
+            };
+            selftest::run(&output_dir, &source.old_client, kind, &new_files, &rules, &cache_refs)?;
+        }
+        if emit_rollback {
+            println!("Self-test: checking the rollback package");
+            selftest::run(&rollback_dir, &new_client_path, selftest::PackageKind::Hdiff, &sources[0].old_files, &rules, &[])?;
+        }
+    }
+
+    if let Some(format) = archive_format {
+        archive_package(&output_dir, format, volume_size, &cache_refs)?;
+        if emit_rollback {
+            archive_package(&rollback_dir, format, volume_size, &[])?;
+        }
+    }
     
     Ok(())
 }
+
+/// Diffs one client against another. With a `dir_scope`, the files it covers go into one directory diff instead.
+fn diff_clients(
+    (old_root, old_files): (&Path, &HashMap<String, scan::FileMeta>),
+    (new_root, new_files): (&Path, &HashMap<String, scan::FileMeta>),
+    work_dir: &Path,
+    rules: &Rules,
+    diffs: &DiffCache,
+    dir_scope: Option<&dirdiff::DirScope>,
+    use_faster_check: bool,
+) -> std::io::Result<ClientDiff> {
+    let mut delete_list = Vec::new();
+    let mut hdiff_entries = Vec::new();
+
+    let plan = dir_scope.map(|scope| dirdiff::DirPlan::new(scope, old_files, new_files));
+    let (old_files, new_files) = match &plan {
+        Some(plan) => (&plan.old_rest, &plan.new_rest),
+        None => (old_files, new_files),
+    };
+
+    let mut full_copies = process_regular_files(old_files, new_files, work_dir, &mut delete_list, &mut hdiff_entries, rules, diffs)?;
+
+    let block_entries = block::generate_block_map(old_files, new_files, work_dir, &mut delete_list, &mut full_copies, diffs, use_faster_check)?;
+    hdiff_entries.extend(block_entries);
+
+    let file_ops = fileops::detect_file_ops(old_files, new_files, work_dir, &mut hdiff_entries, &mut full_copies, rules);
+
+    if let (Some(scope), Some(plan)) = (dir_scope, &plan) {
+        plan.write(scope, old_root, new_root, work_dir, diffs)?;
+        delete_list.extend(plan.deleted.iter().cloned());
+        delete_list.sort();
+    }
+    Ok(ClientDiff { hdiff_entries, full_copies, delete_list, file_ops })
+}
+
+/// The forward package replaces pkg_version with a generated one, which the rollback was not
+/// diffed against, so the rollback restores the old client's copy or deletes it if it had none.
+fn roll_back_pkg_version(rollback: &mut ClientDiff, rollback_dir: &Path, old_files: &HashMap<String, scan::FileMeta>) {
+    let ClientDiff { hdiff_entries, full_copies, delete_list, file_ops } = rollback;
+    drop_pkg_version_entries(rollback_dir, hdiff_entries, full_copies, file_ops, delete_list);
+
+    match old_files.get(PKG_VERSION_FILE) {
+        Some(meta) => rollback.full_copies.push((PKG_VERSION_FILE.to_string(), meta.clone())),
+        None => {
+            rollback.delete_list.push(PKG_VERSION_FILE.to_string());
+            rollback.delete_list.sort();
+        }
+    }
+}
+
+/// Drops every entry that writes or deletes pkg_version, and the patch files they used.
+fn drop_pkg_version_entries(
+    dir: &Path,
+    hdiff_entries: &mut Vec<BlockPatchEntry>,
+    full_copies: &mut Vec<(String, scan::FileMeta)>,
+    file_ops: &mut Vec<fileops::FileOp>,
+    delete_list: &mut Vec<String>,
+) {
+    for entry in hdiff_entries.iter().filter(|entry| entry.target_file_name == PKG_VERSION_FILE) {
+        let _ = fs::remove_file(dir.join(&entry.patch_file_name));
+    }
+    hdiff_entries.retain(|entry| entry.target_file_name != PKG_VERSION_FILE);
+    full_copies.retain(|(rel_path, _)| rel_path != PKG_VERSION_FILE);
+    file_ops.retain(|op| op.target_file_name != PKG_VERSION_FILE);
+    delete_list.retain(|rel_path| rel_path != PKG_VERSION_FILE);
+}
+
+fn sign_package(dir: &Path, key: &common::signing::SigningKey, exclude: &[&str]) -> std::io::Result<()> {
+    let count = common::signing::sign_package(dir, key, exclude).map_err(|e| std::io::Error::other(format!("Signing error: {}", e)))?;
+    println!("Signed {} ({} files)", dir.display(), count);
+    Ok(())
+}
+
+/// Packs `dir` into an archive next to it, named after it, with a checksum file alongside.
+fn archive_package(dir: &Path, format: ArchiveFormat, volume_size: Option<u64>, exclude: &[&str]) -> std::io::Result<()> {
+    let name = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let archive = dir.with_file_name(format!("{}.{}", name, format.extension()));
+    println!("Archiving {}...", dir.display());
+    let volumes = common::archive::create(dir, &archive, format, volume_size, exclude)
+        .map_err(|e| std::io::Error::other(format!("Archive error: {}", e)))?;
+    let size: u64 = volumes.iter().filter_map(|volume| fs::metadata(volume).ok()).map(|meta| meta.len()).sum();
+    println!(
+        "Wrote {} ({} volume{}, {:.1} MiB) and {}",
+        archive.display(),
+        volumes.len(),
+        if volumes.len() == 1 { "" } else { "s" },
+        size as f64 / 1024.0 / 1024.0,
+        common::archive::checksum_path(&archive).display()
+    );
+    Ok(())
+}
+
+fn write_hdiffmap(
+    dir: &Path,
+    hdiff_entries: &[BlockPatchEntry],
+    full_copies: &[(String, scan::FileMeta)],
+    delete_list: &[String],
+    file_ops: &[fileops::FileOp],
+) -> std::io::Result<()> {
+    let copy_entries = payload::write_payloads(full_copies, dir)?;
+    let json_data = serde_json::to_string_pretty(&HdiffMap { diff_map: hdiff_entries, copy_map: &copy_entries })?;
+    fs::write(dir.join("hdiffmap.json"), json_data)?;
+
+    fs::write(dir.join("deletefiles.txt"), delete_list.join("\n"))?;
+
+    if !file_ops.is_empty() {
+        let json_data = serde_json::to_string_pretty(&FileOps { operations: file_ops })?;
+        fs::write(dir.join(fileops::FILE_OPS_NAME), json_data)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::{collections::HashMap, fs, path::Path, time::SystemTime};
+
+use serde::Serialize;
+
+use crate::{rules::{Rules, RulesSummary}, scan::FileMeta};
+
+pub const PACKAGE_INFO_NAME: &str = "package_info.json";
+
+/// Describes a package so the patcher can tell what it applies to before touching anything.
+#[derive(Serialize)]
+pub struct PackageInfo<'a> {
+    pub format: &'static str,
+    pub game: Option<String>,
+    pub source_versions: Vec<String>,
+    pub target_version: Option<String>,
+    pub created_at: String,
+    pub tool_version: &'static str,
+    pub package_size: u64,
+    pub target_file_count: usize,
+    pub target_size: u64,
+    pub rules: RulesSummary<'a>,
+}
+
+impl<'a> PackageInfo<'a> {
+    /// `target_path` and `target_files` are the client the package produces.
+    pub fn new(
+        format: &'static str,
+        source_versions: Vec<String>,
+        target_path: &Path,
+        target_files: &HashMap<String, FileMeta>,
+        package_size: u64,
+        rules: &'a Rules,
+    ) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let target_files: Vec<_> = target_files.iter().filter(|(rel_path, _)| !rules.is_skipped(rel_path)).collect();
+
+        Self {
+            format,
+            game: common::version::detect_game(target_path),
+            source_versions,
+            target_version: common::version::detect_version(target_path).map(|version| version.to_string()),
+            created_at: common::utils::format_utc(created_at),
+            tool_version: env!("CARGO_PKG_VERSION"),
+            package_size,
+            target_file_count: target_files.len(),
+            target_size: target_files.iter().map(|(_, meta)| meta.size).sum(),
+            rules: rules.summary(),
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> std::io::Result<()> {
+        fs::write(dir.join(PACKAGE_INFO_NAME), serde_json::to_string_pretty(self)?)
+    }
+}
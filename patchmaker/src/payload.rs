@@ -0,0 +1,50 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::Serialize;
+
+use crate::scan::FileMeta;
+
+pub const PAYLOAD_DIR: &str = "payloads";
+
+/// A file shipped whole: the patcher copies the payload to the target and checks its MD5.
+#[derive(Debug, Serialize)]
+pub struct CopyEntry {
+    pub target_file_name: String,
+    pub file_md5: String,
+    pub file_size: u64,
+    pub payload_file_name: String,
+}
+
+/// Stores each distinct content among `full_copies` once under `payloads/<md5>` and returns an
+/// entry for every target path.
+pub fn write_payloads(full_copies: &[(String, FileMeta)], output_dir: &Path) -> std::io::Result<Vec<CopyEntry>> {
+    let mut payloads: HashMap<(&str, u64), String> = HashMap::new();
+    let mut entries = Vec::new();
+    for (rel_path, meta) in full_copies {
+        let payload_file_name = match payloads.get(&(meta.md5.as_str(), meta.size)) {
+            Some(name) => name.clone(),
+            None => {
+                let name = format!("{}/{}", PAYLOAD_DIR, meta.md5);
+                let payload_path = output_dir.join(&name);
+                if let Some(parent) = payload_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(&meta.full_path, &payload_path)?;
+                payloads.insert((meta.md5.as_str(), meta.size), name.clone());
+                name
+            }
+        };
+        entries.push(CopyEntry {
+            target_file_name: rel_path.clone(),
+            file_md5: meta.md5.clone(),
+            file_size: meta.size,
+            payload_file_name,
+        });
+    }
+    entries.sort_by(|a, b| a.target_file_name.cmp(&b.target_file_name));
+
+    if entries.len() > payloads.len() {
+        println!("Stored {} payloads for {} copied files", payloads.len(), entries.len());
+    }
+    Ok(entries)
+}
@@ -0,0 +1,176 @@
+use std::{fs, path::Path};
+
+use common::embedded::DiffOptions;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+/// Built-in rules, applied after any user rules since the first matching pattern wins.
+const DEFAULT_RULES: &str = r#"
+[[rule]]
+pattern = "**/Persistent/**"
+action = "skip"
+
+[[rule]]
+pattern = "**/SDKCaches/**"
+action = "skip"
+
+[[rule]]
+pattern = "**/*.pck"
+action = "diff"
+
+[[rule]]
+pattern = "**/Plugins/x86_64/**/*.dll"
+action = "diff"
+"#;
+
+#[derive(thiserror::Error, Debug)]
+pub enum RulesError {
+    #[error("Failed to read rules file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse rules file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid pattern {0}: {1}")]
+    Pattern(String, #[source] globset::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Diff,
+    Copy,
+    Skip,
+    /// Handled like an unmatched file, but never added to the delete list.
+    NeverDelete,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Thresholds {
+    /// Changed files smaller than this are copied even when a rule says diff.
+    #[serde(default)]
+    pub min_diff_size: u64,
+    /// A diff larger than this fraction of the new file is replaced by a full copy.
+    #[serde(default)]
+    pub max_patch_ratio: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct RuleEntry {
+    pattern: String,
+    action: Action,
+    /// hdiffz options for matching files, on top of the global ones.
+    #[serde(default)]
+    hdiff: Option<DiffOptions>,
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleEntry>,
+    thresholds: Option<Thresholds>,
+    hdiff: Option<DiffOptions>,
+}
+
+/// What a package was built with, recorded in its metadata.
+#[derive(Serialize)]
+pub struct RulesSummary<'a> {
+    /// The user's rules file verbatim, absent when only the built-in rules applied.
+    pub rules_file: Option<&'a str>,
+    pub fallback: Action,
+    pub hdiff_args: Vec<String>,
+}
+
+pub struct Rules {
+    source: Option<String>,
+    patterns: GlobSet,
+    actions: Vec<Action>,
+    rule_diff_options: Vec<Option<DiffOptions>>,
+    fallback: Action,
+    thresholds: Thresholds,
+    diff_options: DiffOptions,
+}
+
+impl Rules {
+    /// `fallback` applies to changed files no pattern matches, either diff or copy.
+    /// `diff_options` come from the command line and win over the file's `[hdiff]` table.
+    pub fn load(path: Option<&Path>, fallback: Action, diff_options: &DiffOptions) -> Result<Self, RulesError> {
+        let defaults: RulesFile = toml::from_str(DEFAULT_RULES)?;
+        let source = path.map(fs::read_to_string).transpose()?;
+        let user = match &source {
+            Some(source) => toml::from_str(source)?,
+            None => RulesFile { rules: Vec::new(), thresholds: None, hdiff: None },
+        };
+        let diff_options = diff_options.or(&user.hdiff.unwrap_or_default());
+
+        let mut builder = GlobSetBuilder::new();
+        let mut actions = Vec::new();
+        let mut rule_diff_options = Vec::new();
+        for entry in user.rules.into_iter().chain(defaults.rules) {
+            let glob = GlobBuilder::new(&entry.pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| RulesError::Pattern(entry.pattern.clone(), e))?;
+            builder.add(glob);
+            actions.push(entry.action);
+            rule_diff_options.push(entry.hdiff.map(|options| options.or(&diff_options)));
+        }
+        let patterns = builder.build().map_err(|e| RulesError::Pattern(String::new(), e))?;
+
+        Ok(Self {
+            source,
+            patterns,
+            actions,
+            rule_diff_options,
+            fallback,
+            thresholds: user.thresholds.unwrap_or_default(),
+            diff_options,
+        })
+    }
+
+    pub fn summary(&self) -> RulesSummary<'_> {
+        RulesSummary { rules_file: self.source.as_deref(), fallback: self.fallback, hdiff_args: self.diff_options.args() }
+    }
+
+    /// Action of the first pattern matching `rel_path`, or the fallback when none does.
+    pub fn action(&self, rel_path: &str) -> Action {
+        self.patterns
+            .matches(rel_path)
+            .into_iter()
+            .min()
+            .map(|index| self.actions[index])
+            .unwrap_or(self.fallback)
+    }
+
+    /// hdiffz options of the first matching rule that sets any, or the global ones.
+    pub fn diff_options(&self, rel_path: &str) -> &DiffOptions {
+        let mut matches = self.patterns.matches(rel_path);
+        matches.sort_unstable();
+        matches
+            .into_iter()
+            .find_map(|index| self.rule_diff_options[index].as_ref())
+            .unwrap_or(&self.diff_options)
+    }
+
+    pub fn is_skipped(&self, rel_path: &str) -> bool {
+        self.action(rel_path) == Action::Skip
+    }
+
+    pub fn never_delete(&self, rel_path: &str) -> bool {
+        self.action(rel_path) == Action::NeverDelete
+    }
+
+    /// Whether a changed file should be diffed rather than shipped whole.
+    pub fn wants_diff(&self, rel_path: &str, new_size: u64) -> bool {
+        let action = match self.action(rel_path) {
+            Action::NeverDelete => self.fallback,
+            action => action,
+        };
+        action == Action::Diff && new_size >= self.thresholds.min_diff_size
+    }
+
+    pub fn diff_worth_keeping(&self, patch_size: u64, new_size: u64) -> bool {
+        self.thresholds
+            .max_patch_ratio
+            .map(|ratio| patch_size as f64 <= new_size as f64 * ratio)
+            .unwrap_or(true)
+    }
+}
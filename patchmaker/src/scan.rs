@@ -1,15 +1,42 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, time::UNIX_EPOCH};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+const SCAN_CACHE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMeta {
     pub full_path: std::path::PathBuf,
     pub md5: String,
     pub size: u64,
+    #[serde(default)]
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScanCache {
+    version: u32,
+    root: PathBuf,
+    files: HashMap<String, FileMeta>,
+}
+
+pub fn wants_signature(rel_path: &str) -> bool {
+    rel_path.ends_with(".block") && rel_path.contains("StreamingAssets/Asb")
 }
 
-pub fn scan_files(root: &Path) -> std::io::Result<HashMap<String, FileMeta>> {
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Scans `root`, reusing hashes from `previous` for files whose size and mtime are unchanged.
+pub fn scan_files(root: &Path, previous: &HashMap<String, FileMeta>) -> std::io::Result<HashMap<String, FileMeta>> {
     let entries: Vec<_> = walkdir::WalkDir::new(root)
         .into_iter()
         .filter_map(|e| e.ok())
@@ -17,34 +44,108 @@ pub fn scan_files(root: &Path) -> std::io::Result<HashMap<String, FileMeta>> {
         .collect();
 
     let pb = common::utils::create_progress_bar(entries.len());
+    let rehashed = AtomicUsize::new(0);
 
     let map: HashMap<_, _> = entries.into_par_iter().filter_map(|entry| {
         let path = entry.path().to_path_buf();
         let rel_path = path.strip_prefix(root).ok()?.to_string_lossy().replace("\\", "/");
+        let metadata = entry.metadata().ok()?;
+        let size = metadata.len();
+        let mtime = modified_nanos(&metadata);
+
+        if let Some(cached) = previous.get(&rel_path) {
+            let needs_signature = wants_signature(&rel_path) && cached.signature.is_none();
+            if mtime != 0 && cached.mtime == mtime && cached.size == size && !needs_signature {
+                pb.inc(1);
+                let meta = FileMeta { full_path: path, md5: cached.md5.clone(), size, mtime, signature: cached.signature.clone() };
+                return Some((rel_path, meta));
+            }
+        }
+
         let md5 = common::md5::calculate_md5(&path).ok()?;
-        let size = fs::metadata(&path).ok()?.len();
+        let signature = if wants_signature(&rel_path) {
+            crate::similarity::compute_signature(&path).ok()
+        } else {
+            None
+        };
+        rehashed.fetch_add(1, Ordering::Relaxed);
         pb.inc(1);
-        Some((rel_path, FileMeta { full_path: path, md5, size }))
+        Some((rel_path, FileMeta { full_path: path, md5, size, mtime, signature }))
     }).collect();
 
     pb.finish();
+
+    if !previous.is_empty() {
+        let added = map.keys().filter(|rel_path| !previous.contains_key(*rel_path)).count();
+        let removed = previous.keys().filter(|rel_path| !map.contains_key(*rel_path)).count();
+        println!(
+            "{}: {} changed, {} added, {} removed since the last scan",
+            root.display(),
+            rehashed.into_inner() - added,
+            added,
+            removed
+        );
+    }
     Ok(map)
 }
 
-pub fn save_cache(path: &Path, map: &HashMap<String, FileMeta>) -> std::io::Result<()> {
-    fs::write(path, serde_json::to_string_pretty(map).unwrap())
+fn save_cache(path: &Path, root: &Path, map: HashMap<String, FileMeta>) -> std::io::Result<HashMap<String, FileMeta>> {
+    let cache = ScanCache { version: SCAN_CACHE_VERSION, root: root.to_path_buf(), files: map };
+    fs::write(path, serde_json::to_string_pretty(&cache).unwrap())?;
+    Ok(cache.files)
 }
 
-pub fn load_cache(path: &Path) -> Option<HashMap<String, FileMeta>> {
-    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+fn load_cache(path: &Path) -> Option<ScanCache> {
+    let cache: ScanCache = match serde_json::from_str(&fs::read_to_string(path).ok()?) {
+        Ok(cache) => cache,
+        Err(_) => {
+            eprintln!("Ignoring {}, it is not a scan cache this patchmaker understands", path.display());
+            return None;
+        }
+    };
+    if cache.version != SCAN_CACHE_VERSION {
+        eprintln!("Ignoring {}, it has scan cache version {} instead of {}", path.display(), cache.version, SCAN_CACHE_VERSION);
+        return None;
+    }
+    if let Some((rel_path, e)) = cache.files.keys().find_map(|rel_path| common::safe_path::sanitize(rel_path).err().map(|e| (rel_path, e))) {
+        eprintln!("Ignoring {}, it contains an unsafe path {}: {}", path.display(), rel_path, e);
+        return None;
+    }
+    Some(cache)
 }
 
 pub fn load_or_scan(root: &Path, cache_path: &Path) -> std::io::Result<HashMap<String, FileMeta>> {
-    if let Some(map) = load_cache(cache_path) {
-        Ok(map)
+    let root = std::path::absolute(root)?;
+    if !root.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is not a directory", root.display())));
+    }
+    let previous = match load_cache(cache_path) {
+        Some(cache) if cache.root != root => {
+            return Err(std::io::Error::other(format!(
+                "{} was scanned from {}, not {}. Delete it to rescan",
+                cache_path.display(),
+                cache.root.display(),
+                root.display()
+            )));
+        }
+        Some(cache) => cache.files,
+        None => HashMap::new(),
+    };
+
+    let map = scan_files(&root, &previous)?;
+    save_cache(cache_path, &root, map)
+}
+
+/// Loads an old client either from its directory or from a scan cache, which is refreshed against the root it records.
+/// Returns the client root along with its files.
+pub fn load_source(source: &Path, cache_path: &Path) -> std::io::Result<(PathBuf, HashMap<String, FileMeta>)> {
+    if source.is_file() {
+        let cache = load_cache(source)
+            .ok_or_else(|| std::io::Error::other(format!("{} is not a valid scan cache", source.display())))?;
+        let map = load_or_scan(&cache.root, source)?;
+        Ok((cache.root, map))
     } else {
-        let map = scan_files(root)?;
-        save_cache(cache_path, &map)?;
-        Ok(map)
+        let map = load_or_scan(source, cache_path)?;
+        Ok((std::path::absolute(source)?, map))
     }
 }
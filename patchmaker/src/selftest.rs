@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use patcher::options::{hdiff::HdiffHandler, ldiff::handler::LdiffHandler};
+use rayon::prelude::*;
+
+use crate::{rules::Rules, scan::FileMeta};
+
+const MAX_REPORTED: usize = 50;
+
+pub enum PackageKind<'a> {
+    Hdiff,
+    Ldiff { version_tag: &'a str },
+}
+
+/// Applies the package in `package_dir` out of place on top of `old_client`, exactly like the
+/// patcher does, and compares the result against the scan of the target client. Ldiff packages
+/// are applied in place on a copy of `old_client` as well, since that deletes originals as it goes.
+pub fn run(
+    package_dir: &Path,
+    old_client: &Path,
+    kind: PackageKind,
+    new_files: &HashMap<String, FileMeta>,
+    rules: &Rules,
+    exclude: &[&str],
+) -> io::Result<()> {
+    let scratch = crate::utils::sibling_dir(package_dir, "selftest");
+    println!("Self-test: staging package in {}...", scratch.display());
+    stage(&scratch, &[(package_dir, exclude)])?;
+
+    match kind {
+        PackageKind::Hdiff => {
+            if !HdiffHandler::with_source(&scratch, old_client).apply() {
+                eprintln!("Self-test: the patcher reported failures");
+            }
+        }
+        PackageKind::Ldiff { version_tag } => {
+            println!("Self-test: applying version tag {}", version_tag);
+            apply_ldiff(&LdiffHandler::with_source(&scratch, old_client), version_tag)?;
+            verify(&scratch, new_files, rules)?;
+
+            println!("Self-test: applying version tag {} in place on a copy of {}...", version_tag, old_client.display());
+            stage(&scratch, &[(old_client, &[]), (package_dir, exclude)])?;
+            apply_ldiff(&LdiffHandler::new(&scratch), version_tag)?;
+        }
+    }
+    verify(&scratch, new_files, rules)
+}
+
+fn apply_ldiff(handler: &LdiffHandler, version_tag: &str) -> io::Result<()> {
+    let manifest_proto = handler
+        .get_manifest_proto()
+        .ok_or_else(|| io::Error::other("Self-test: failed to read the generated ldiff manifest"))?;
+    if !handler.apply_version_tag(&manifest_proto, version_tag) {
+        eprintln!("Self-test: the patcher reported failures");
+    }
+    Ok(())
+}
+
+/// Compares the patched copy against the target client, removing it if they match.
+fn verify(scratch: &Path, new_files: &HashMap<String, FileMeta>, rules: &Rules) -> io::Result<()> {
+    println!("Self-test: comparing the patched copy against the target client...");
+    let mismatches = compare(scratch, new_files, rules)?;
+    if mismatches.is_empty() {
+        fs::remove_dir_all(scratch)?;
+        println!("Self-test passed");
+        return Ok(());
+    }
+
+    for line in mismatches.iter().take(MAX_REPORTED) {
+        eprintln!("  {}", line);
+    }
+    if mismatches.len() > MAX_REPORTED {
+        eprintln!("  ... and {} more", mismatches.len() - MAX_REPORTED);
+    }
+    Err(io::Error::other(format!(
+        "Self-test failed with {} mismatches, the patched copy was kept at {}",
+        mismatches.len(),
+        scratch.display()
+    )))
+}
+
+/// Reflinks or copies each tree into a fresh `scratch`, later ones on top; hardlinks are avoided
+/// since the patcher deletes and rewrites the files it works on.
+fn stage(scratch: &Path, trees: &[(&Path, &[&str])]) -> io::Result<()> {
+    if scratch.exists() {
+        fs::remove_dir_all(scratch)?;
+    }
+    for (root, exclude) in trees {
+        for entry in walkdir::WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let rel_path = entry.path().strip_prefix(root).map_err(io::Error::other)?;
+            if rel_path.to_str().is_some_and(|name| exclude.contains(&name)) {
+                continue;
+            }
+
+            let target = scratch.join(rel_path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if target.exists() {
+                fs::remove_file(&target)?;
+            }
+            reflink_copy::reflink_or_copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn compare(scratch: &Path, new_files: &HashMap<String, FileMeta>, rules: &Rules) -> io::Result<Vec<String>> {
+    let entries: Vec<_> = walkdir::WalkDir::new(scratch)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .collect();
+
+    let pb = common::utils::create_progress_bar(entries.len());
+    let actual: HashMap<String, (String, u64)> = entries
+        .into_par_iter()
+        .filter_map(|entry| {
+            let rel_path = entry.path().strip_prefix(scratch).ok()?.to_string_lossy().replace("\\", "/");
+            pb.inc(1);
+            if rules.is_skipped(&rel_path) {
+                return None;
+            }
+            let md5 = common::md5::calculate_md5(&entry.path().to_path_buf()).ok()?;
+            let size = entry.metadata().ok()?.len();
+            Some((rel_path, (md5, size)))
+        })
+        .collect();
+    pb.finish();
+
+    let mut mismatches = Vec::new();
+    for (rel_path, new_meta) in new_files.iter().filter(|(rel_path, _)| !rules.is_skipped(rel_path)) {
+        match actual.get(rel_path) {
+            None => mismatches.push(format!("missing: {}", rel_path)),
+            Some((md5, size)) if *md5 != new_meta.md5 || *size != new_meta.size => {
+                mismatches.push(format!("wrong content: {} (expected {}, got {})", rel_path, new_meta.md5, md5));
+            }
+            Some(_) => {}
+        }
+    }
+    mismatches.extend(
+        actual
+            .keys()
+            .filter(|rel_path| !new_files.contains_key(*rel_path))
+            .map(|rel_path| format!("unexpected: {}", rel_path)),
+    );
+    mismatches.sort();
+    Ok(mismatches)
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, fs::File, io::{self, BufReader, Read}, path::Path, sync::LazyLock};
+
+pub const SIGNATURE_LEN: usize = 64;
+const BAND_ROWS: usize = 4;
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+// Top 13 bits of the gear hash, roughly 8 KiB average chunks
+const CHUNK_MASK: u64 = ((1 << 13) - 1) << (64 - 13);
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+static GEAR: LazyLock<[u64; 256]> = LazyLock::new(|| {
+    let mut state = 0x9e3779b97f4a7c15;
+    std::array::from_fn(|_| splitmix64(&mut state))
+});
+
+static SEEDS: LazyLock<[u64; SIGNATURE_LEN]> = LazyLock::new(|| {
+    let mut state = 0x2545f4914f6cdd1d;
+    std::array::from_fn(|_| splitmix64(&mut state))
+});
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    mix(*state)
+}
+
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Splits the file into content-defined chunks and returns a MinHash over the chunk hashes.
+/// Insertions only disturb the chunks around them, so shifted data still shares most slots.
+pub fn compute_signature(path: &Path) -> io::Result<Vec<u64>> {
+    let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut signature = vec![u64::MAX; SIGNATURE_LEN];
+
+    let mut gear = 0u64;
+    let mut chunk_hash = FNV_OFFSET;
+    let mut chunk_len = 0usize;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+
+        for &byte in &buf[..read] {
+            gear = (gear << 1).wrapping_add(GEAR[byte as usize]);
+            chunk_hash = (chunk_hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+            chunk_len += 1;
+
+            if (chunk_len >= MIN_CHUNK_SIZE && gear & CHUNK_MASK == 0) || chunk_len >= MAX_CHUNK_SIZE {
+                add_chunk(&mut signature, chunk_hash);
+                gear = 0;
+                chunk_hash = FNV_OFFSET;
+                chunk_len = 0;
+            }
+        }
+    }
+
+    if chunk_len > 0 {
+        add_chunk(&mut signature, chunk_hash);
+    }
+    Ok(signature)
+}
+
+fn add_chunk(signature: &mut [u64], chunk_hash: u64) {
+    for (slot, seed) in signature.iter_mut().zip(SEEDS.iter()) {
+        *slot = (*slot).min(mix(chunk_hash ^ seed));
+    }
+}
+
+/// Estimated Jaccard similarity of the two files' chunk sets, 0.0 to 1.0.
+pub fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let matching = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    matching as f64 / a.len() as f64
+}
+
+/// Locality-sensitive index over MinHash signatures, bucketed by bands of `BAND_ROWS` slots.
+#[derive(Default)]
+pub struct SimilarityIndex<'a, T> {
+    entries: Vec<(T, &'a [u64])>,
+    bands: HashMap<(usize, u64), Vec<usize>>,
+}
+
+impl<'a, T: Copy> SimilarityIndex<'a, T> {
+    pub fn insert(&mut self, item: T, signature: &'a [u64]) {
+        let id = self.entries.len();
+        for key in band_keys(signature) {
+            self.bands.entry(key).or_default().push(id);
+        }
+        self.entries.push((item, signature));
+    }
+
+    /// Returns every indexed item sharing at least one band with `signature`, most similar first.
+    pub fn query(&self, signature: &[u64]) -> Vec<(T, f64)> {
+        let mut ids: Vec<usize> = band_keys(signature)
+            .filter_map(|key| self.bands.get(&key))
+            .flatten()
+            .copied()
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut matches: Vec<_> = ids
+            .into_iter()
+            .map(|id| {
+                let (item, indexed) = self.entries[id];
+                (item, similarity(signature, indexed))
+            })
+            .collect();
+        matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        matches
+    }
+}
+
+fn band_keys(signature: &[u64]) -> impl Iterator<Item = (usize, u64)> + '_ {
+    signature
+        .chunks(BAND_ROWS)
+        .enumerate()
+        .map(|(band, rows)| (band, rows.iter().fold(FNV_OFFSET, |acc, row| mix(acc ^ row))))
+}
@@ -1,4 +1,22 @@
-use std::{collections::HashSet, fs::{self, File}, io::{self, Read}, path::Path};
+use std::{collections::HashSet, fs, path::{Path, PathBuf}, sync::{Condvar, Mutex}};
+
+/// A folder next to `output_dir`, named after it with `suffix` appended.
+pub fn sibling_dir(output_dir: &Path, suffix: &str) -> PathBuf {
+    let name = output_dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    output_dir.with_file_name(format!("{}_{}", name, suffix))
+}
+
+/// Total size of the files under `dir`, leaving out the top-level files named in `exclude`.
+pub fn folder_size(dir: &Path, exclude: &[&str]) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.path().strip_prefix(dir).ok().and_then(|rel| rel.to_str()).is_none_or(|rel| !exclude.contains(&rel)))
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|meta| meta.len())
+        .sum()
+}
 
 pub fn clear_directory(output_dir: &Path, keep_files: HashSet<&&str>) -> std::io::Result<()>{
     for entry in fs::read_dir(&output_dir)? {
@@ -17,46 +35,38 @@ pub fn clear_directory(output_dir: &Path, keep_files: HashSet<&&str>) -> std::io
     Ok(())
 }
 
-use crc32fast::Hasher;
-
-pub fn block_diff_percent_experimental(path_a: &Path, path_b: &Path) -> io::Result<f64> {
-    const BLOCK_SIZE: usize = 64 * 1024; 
-    let mut file_a = File::open(path_a)?;
-    let mut file_b = File::open(path_b)?;
-
-    let mut buf_a = vec![0u8; BLOCK_SIZE];
-    let mut buf_b = vec![0u8; BLOCK_SIZE];
-
-    let mut blocks_a = Vec::new();
-    let mut blocks_b = Vec::new();
-
-    loop {
-        let read_a = file_a.read(&mut buf_a)?;
-        if read_a == 0 { break; }
-        let mut hasher = Hasher::new();
-        hasher.update(&buf_a[..read_a]);
-        blocks_a.push(hasher.finalize());
-    }
+/// Caps the summed memory estimate of jobs running at once. A job bigger than the
+/// whole budget still runs, but only once nothing else holds a reservation.
+pub struct MemoryBudget {
+    limit: u64,
+    in_use: Mutex<u64>,
+    released: Condvar,
+}
 
-    loop {
-        let read_b = file_b.read(&mut buf_b)?;
-        if read_b == 0 { break; }
-        let mut hasher = Hasher::new();
-        hasher.update(&buf_b[..read_b]);
-        blocks_b.push(hasher.finalize());
-    }
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    amount: u64,
+}
 
-    let len = blocks_a.len().max(blocks_b.len());
-    if len == 0 {
-        return Ok(0.0);
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self { limit, in_use: Mutex::new(0), released: Condvar::new() }
     }
 
-    let mut matching = 0usize;
-    for (a, b) in blocks_a.iter().zip(blocks_b.iter()) {
-        if a == b {
-            matching += 1;
+    pub fn reserve(&self, amount: u64) -> Reservation<'_> {
+        let amount = amount.min(self.limit);
+        let mut in_use = self.in_use.lock().unwrap();
+        while *in_use + amount > self.limit {
+            in_use = self.released.wait(in_use).unwrap();
         }
+        *in_use += amount;
+        Reservation { budget: self, amount }
     }
+}
 
-    Ok(100.0 - (matching as f64 / len as f64) * 100.0)
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.in_use.lock().unwrap() -= self.amount;
+        self.budget.released.notify_all();
+    }
 }
@@ -2,24 +2,109 @@ use anyhow::Result;
 use std::env;
 use std::path::Path;
 use sophon::SophonClient;
+use sophon::modules::{read_manifest, ChunkStore, SophonManifestBuilder, SophonMirror, CHUNK_DIR, MANIFEST_DIR};
 use sophon::utils::read_input;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("build") {
+        return build(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("serve") {
+        return serve(&args[2..]).await;
+    }
+    if args.get(1).map(String::as_str) == Some("gc") {
+        return gc(&args[2..]);
+    }
 
-    let manifest_url = args.get(1).cloned().unwrap_or_else(|| read_input("Enter manifest URL: "));
-    let manifest_file = args.get(2).cloned().unwrap_or_else(|| read_input("Enter manifest file name: "));
-    let chunk_url = args.get(3).cloned().unwrap_or_else(|| read_input("Enter chunk URL: "));
-    let output_dir = args.get(4).cloned().unwrap_or_else(|| read_input("Enter output directory: "));
+    // `<manifest url> <manifest file> <chunk url> <output dir> [--store=DIR] [--store-size=50g]`
+    let (flags, positional): (Vec<&String>, Vec<&String>) = args[1..].iter().partition(|arg| arg.starts_with("--"));
+    let flag = |name: &str| flags.iter().find_map(|arg| arg.strip_prefix(name));
+    let manifest_url = positional.first().map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter manifest URL: "));
+    let manifest_file = positional.get(1).map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter manifest file name: "));
+    let chunk_url = positional.get(2).map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter chunk URL: "));
+    let output_dir = positional.get(3).map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter output directory: "));
     
     if !Path::new(&output_dir).exists() {
         std::fs::create_dir_all(&output_dir)?;
     }
 
-    let client = SophonClient::new(&manifest_url, &manifest_file, &chunk_url);
+    let mut client = SophonClient::new(&manifest_url, &manifest_file, &chunk_url);
+    if let Some(store_dir) = flag("--store=") {
+        let mut store = ChunkStore::open(Path::new(store_dir))?;
+        if let Some(size) = flag("--store-size=") {
+            store = store.max_size(common::utils::parse_size(size).ok_or_else(|| anyhow::anyhow!("Invalid store size: {}", size))?);
+        }
+        client = client.chunk_store(store);
+    }
     client.download_game(&output_dir).await?;
 
     println!("Download complete!");
     Ok(())
 }
+
+/// `build <source dir> <output dir> [--chunk-size=1m] [--level=3] [--manifest-name=NAME]`
+fn build(args: &[String]) -> Result<()> {
+    let (flags, positional): (Vec<&String>, Vec<&String>) = args.iter().partition(|arg| arg.starts_with("--"));
+    let flag = |name: &str| flags.iter().find_map(|arg| arg.strip_prefix(name));
+
+    let source_dir = positional.first().map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter source directory: "));
+    let output_dir = positional.get(1).map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter output directory: "));
+    let mut builder = SophonManifestBuilder::default();
+    if let Some(size) = flag("--chunk-size=") {
+        let size = common::utils::parse_size(size).ok_or_else(|| anyhow::anyhow!("Invalid chunk size: {}", size))?;
+        builder = builder.chunk_size(size);
+    }
+    if let Some(level) = flag("--level=") {
+        builder = builder.compression_level(level.parse().map_err(|_| anyhow::anyhow!("Invalid compression level: {}", level))?);
+    }
+
+    let summary = builder.build(Path::new(&source_dir), Path::new(&output_dir), flag("--manifest-name="))?;
+    println!(
+        "Built manifest for {} assets: {} chunks written ({:.1} MiB), {} reused",
+        summary.asset_count,
+        summary.chunks_written,
+        summary.stored_size as f64 / 1024.0 / 1024.0,
+        summary.chunks_reused
+    );
+    println!("Manifest URL: <host>/{}, manifest file: {}", MANIFEST_DIR, summary.manifest_name);
+    println!("Chunk URL: <host>/{}", CHUNK_DIR);
+    Ok(())
+}
+
+/// `serve <store dir> [--listen=0.0.0.0:8080] [--manifest-upstream=URL] [--chunk-upstream=URL]`
+async fn serve(args: &[String]) -> Result<()> {
+    let (flags, positional): (Vec<&String>, Vec<&String>) = args.iter().partition(|arg| arg.starts_with("--"));
+    let flag = |name: &str| flags.iter().find_map(|arg| arg.strip_prefix(name));
+
+    let store_dir = positional.first().map(|arg| arg.to_string()).unwrap_or_else(|| read_input("Enter store directory: "));
+    let mut mirror = SophonMirror::new(Path::new(&store_dir));
+    if let Some(url) = flag("--manifest-upstream=") {
+        mirror = mirror.manifest_upstream(url);
+    }
+    if let Some(url) = flag("--chunk-upstream=") {
+        mirror = mirror.chunk_upstream(url);
+    }
+    mirror.serve(flag("--listen=").unwrap_or("0.0.0.0:8080")).await
+}
+
+/// `gc <store dir> <manifest>...`: keeps only the chunks the given manifest files reference.
+fn gc(args: &[String]) -> Result<()> {
+    let Some((store_dir, manifest_paths)) = args.split_first() else {
+        anyhow::bail!("Usage: sophon gc <store dir> <manifest>...");
+    };
+    if manifest_paths.is_empty() {
+        anyhow::bail!("Refusing to collect without any manifest to keep chunks for");
+    }
+
+    let manifests = manifest_paths.iter().map(|path| read_manifest(Path::new(path))).collect::<Result<Vec<_>>>()?;
+    let cleanup = ChunkStore::open(Path::new(store_dir))?.gc(&manifests)?;
+    println!(
+        "Removed {} chunks ({:.1} MiB), {:.1} MiB kept",
+        cleanup.removed,
+        cleanup.freed as f64 / 1024.0 / 1024.0,
+        cleanup.kept_size as f64 / 1024.0 / 1024.0
+    );
+    Ok(())
+}
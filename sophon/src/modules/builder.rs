@@ -0,0 +1,177 @@
+use std::{fs::{self, File}, io::{BufReader, Read}, path::Path, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+
+use prost::Message;
+use rayon::prelude::*;
+
+use crate::sophon_manifest::{SophonManifestAssetChunk, SophonManifestAssetProperty, SophonManifestProto};
+use super::*;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub const MANIFEST_DIR: &str = "manifest";
+pub const CHUNK_DIR: &str = "chunk";
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+/// The downloader only checks for non-zero, which it treats as a directory to create.
+const DIRECTORY_ASSET_TYPE: i32 = 64;
+
+/// Builds a `SophonManifestProto` and its chunk store from a local install. Chunks and the
+/// manifest are raw zstd streams, which is what the downloader hands to 7-Zip, and chunks are
+/// named by the MD5 of their decompressed bytes so identical data is stored once.
+pub struct SophonManifestBuilder {
+    chunk_size: u64,
+    compression_level: i32,
+}
+
+pub struct BuildSummary {
+    pub manifest_name: String,
+    pub asset_count: usize,
+    pub chunks_written: usize,
+    pub chunks_reused: usize,
+    pub stored_size: u64,
+}
+
+impl Default for SophonManifestBuilder {
+    fn default() -> Self {
+        Self { chunk_size: DEFAULT_CHUNK_SIZE, compression_level: DEFAULT_COMPRESSION_LEVEL }
+    }
+}
+
+impl SophonManifestBuilder {
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn compression_level(mut self, compression_level: i32) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    /// Chunks every file under `source_dir` into `output_dir/chunk` and writes the manifest to
+    /// `output_dir/manifest`, named `manifest_name` or after its own MD5.
+    pub fn build(&self, source_dir: &Path, output_dir: &Path, manifest_name: Option<&str>) -> Result<BuildSummary> {
+        let chunk_dir = output_dir.join(CHUNK_DIR);
+        let manifest_dir = output_dir.join(MANIFEST_DIR);
+        fs::create_dir_all(&chunk_dir)?;
+        fs::create_dir_all(&manifest_dir)?;
+
+        let mut files = Vec::new();
+        let mut empty_dirs = Vec::new();
+        for entry in walkdir::WalkDir::new(source_dir).min_depth(1) {
+            let entry = entry?;
+            let rel_path = entry.path().strip_prefix(source_dir)?.to_string_lossy().replace("\\", "/");
+            if entry.file_type().is_file() {
+                files.push((rel_path, entry.path().to_path_buf()));
+            } else if entry.file_type().is_dir() && fs::read_dir(entry.path())?.next().is_none() {
+                empty_dirs.push(rel_path);
+            }
+        }
+        files.sort();
+        empty_dirs.sort();
+
+        println!("Chunking {} files...", files.len());
+        let pb = crate::utils::create_progress_bar(files.len());
+        let counters = Counters::default();
+        let mut assets = files
+            .par_iter()
+            .map(|(rel_path, path)| {
+                let asset = self.chunk_file(rel_path, path, &chunk_dir, &counters);
+                pb.inc(1);
+                asset
+            })
+            .collect::<Result<Vec<_>>>()?;
+        pb.finish();
+
+        assets.extend(empty_dirs.into_iter().map(|rel_path| SophonManifestAssetProperty {
+            asset_name: rel_path,
+            asset_chunks: Vec::new(),
+            asset_type: DIRECTORY_ASSET_TYPE,
+            asset_size: 0,
+            asset_hash_md5: String::new(),
+        }));
+
+        let asset_count = assets.len();
+        let manifest = zstd::encode_all(&*SophonManifestProto { assets }.encode_to_vec(), self.compression_level)?;
+        let manifest_name = match manifest_name {
+            Some(name) => name.to_string(),
+            None => format!("manifest_{:x}", md5::compute(&manifest)),
+        };
+        fs::write(common::safe_path::safe_join(&manifest_dir, &manifest_name)?, &manifest)?;
+
+        Ok(BuildSummary {
+            manifest_name,
+            asset_count,
+            chunks_written: counters.written.load(Ordering::Relaxed),
+            chunks_reused: counters.reused.load(Ordering::Relaxed),
+            stored_size: counters.stored_size.load(Ordering::Relaxed),
+        })
+    }
+
+    fn chunk_file(&self, rel_path: &str, path: &Path, chunk_dir: &Path, counters: &Counters) -> Result<SophonManifestAssetProperty> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut file_md5 = md5::Context::new();
+        let mut asset_chunks = Vec::new();
+        let mut offset = 0u64;
+        let mut buffer = Vec::with_capacity(self.chunk_size as usize);
+
+        loop {
+            buffer.clear();
+            (&mut reader).take(self.chunk_size).read_to_end(&mut buffer)?;
+            if buffer.is_empty() {
+                break;
+            }
+            file_md5.consume(&buffer);
+
+            let chunk_md5 = format!("{:x}", md5::compute(&buffer));
+            let chunk_path = chunk_dir.join(&chunk_md5);
+            let chunk_size = match fs::metadata(&chunk_path) {
+                Ok(meta) => {
+                    counters.reused.fetch_add(1, Ordering::Relaxed);
+                    meta.len()
+                }
+                Err(_) => {
+                    let compressed = zstd::encode_all(buffer.as_slice(), self.compression_level)?;
+                    write_chunk(&chunk_path, &compressed)?;
+                    counters.written.fetch_add(1, Ordering::Relaxed);
+                    counters.stored_size.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+                    compressed.len() as u64
+                }
+            };
+
+            asset_chunks.push(SophonManifestAssetChunk {
+                chunk_name: chunk_md5.clone(),
+                chunk_decompressed_hash_md5: chunk_md5,
+                chunk_on_file_offset: offset as i64,
+                chunk_size: chunk_size as i64,
+                chunk_size_decompressed: buffer.len() as i64,
+            });
+            offset += buffer.len() as u64;
+        }
+
+        Ok(SophonManifestAssetProperty {
+            asset_name: rel_path.to_string(),
+            asset_chunks,
+            asset_type: 0,
+            asset_size: offset as i64,
+            asset_hash_md5: format!("{:x}", file_md5.finalize()),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicUsize,
+    reused: AtomicUsize,
+    stored_size: AtomicU64,
+}
+
+/// Writes through a temporary name, since another file, or another build sharing the chunk
+/// directory, can be storing the same chunk at once.
+fn write_chunk(chunk_path: &Path, bytes: &[u8]) -> Result<()> {
+    let name = chunk_path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = chunk_path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&temp_path, bytes)?;
+    fs::rename(&temp_path, chunk_path)?;
+    Ok(())
+}
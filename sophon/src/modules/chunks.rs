@@ -10,6 +10,7 @@ pub struct SophonChunks {
     downloader: SophonDownloader,
     merger: SophonMerger,
     chunk_url: String,
+    store: Option<Arc<ChunkStore>>,
 }
 
 impl SophonChunks {
@@ -18,9 +19,15 @@ impl SophonChunks {
             downloader,
             merger,
             chunk_url: chunk_url.to_string(),
+            store: None,
         }
     }
 
+    pub fn store(mut self, store: Option<Arc<ChunkStore>>) -> Self {
+        self.store = store;
+        self
+    }
+
     pub async fn parse_manifest_proto(&self, proto: SophonManifestProto, output_dir: &str) -> Result<()> {
         let pb = crate::utils::create_progress_bar(proto.assets.len());
     
@@ -33,14 +40,16 @@ impl SophonChunks {
             let downloader = &self.downloader;
             let merger = &self.merger;
             let chunk_url = self.chunk_url.clone();
+            let store = self.store.clone();
             let output_dir = output_dir.to_string();
             let pb = pb.clone();
     
             asset_futures.push(async move {
                 let _asset_permit = asset_semaphore.acquire_owned().await.unwrap();
     
-                let target_path = format!("{}/{}", output_dir, asset.asset_name);
-                let target_path_obj = Path::new(&target_path);
+                let target_path_obj = common::safe_path::safe_join(Path::new(&output_dir), &asset.asset_name)?;
+                let target_path = target_path_obj.to_string_lossy().to_string();
+                let target_path_obj = target_path_obj.as_path();
     
                 if target_path_obj.exists() { 
                     if let Ok(metadata) = target_path_obj.metadata() {
@@ -61,17 +70,28 @@ impl SophonChunks {
     
                 for chunk in &asset.asset_chunks {
                     let chunk_name = chunk.chunk_name.clone();
+                    let chunk_md5 = chunk.chunk_decompressed_hash_md5.clone();
                     let offset = chunk.chunk_on_file_offset;
                     let chunk_url = chunk_url.clone();
                     let output_dir = output_dir.clone();
                     let downloader = downloader.clone();
                     let chunk_semaphore = chunk_semaphore.clone();
+                    let store = store.clone();
     
                     chunk_futures.push(async move {
+                        if let Some(bytes) = store.as_ref().and_then(|store| store.get(&chunk_name, &chunk_md5)) {
+                            return Ok::<_, anyhow::Error>((offset, bytes));
+                        }
                         let _permit = chunk_semaphore.acquire_owned().await.unwrap();
                         let bytes = downloader
                             .download_and_extract_chunk(&chunk_url, &chunk_name, &output_dir, true)
                             .await?;
+                        // The store is only a cache, so failing to fill it doesn't fail the download
+                        if let Some(store) = &store
+                            && let Err(e) = store.put(&chunk_name, &chunk_md5, &bytes)
+                        {
+                            eprintln!("Failed to store chunk {}: {}", chunk_name, e);
+                        }
                         Ok::<_, anyhow::Error>((offset, bytes))
                     });
                 }
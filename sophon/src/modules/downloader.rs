@@ -24,14 +24,14 @@ impl SophonDownloader {
         let url = format!("{}/{}", url_base, file_name);
         let bytes = self.client.get(&url).send().await?.bytes().await?;
     
-        let tmp_path = dirs::cache_dir().unwrap().join(file_name);
+        let tmp_path = common::safe_path::safe_join(&dirs::cache_dir().unwrap(), file_name)?;
         std::fs::write(&tmp_path, &bytes)?;
     
         let extract_path = Path::new(&extract_path);
         crate::SevenZip::instance()?.extract_to(&tmp_path, extract_path)?;
         std::fs::remove_file(&tmp_path)?;
     
-        let extracted_file_path = extract_path.join(format!("{}~", file_name));
+        let extracted_file_path = common::safe_path::safe_join(extract_path, &format!("{}~", file_name))?;
         let data = std::fs::read(&extracted_file_path)?;
     
         if cleanup {
@@ -69,7 +69,7 @@ impl SophonDownloader {
         cleanup: bool
     ) -> Result<Vec<u8>> {
         let download_path = Path::new(download_path).join("ldiff/");
-        let file_path = download_path.join(chunk_file);
+        let file_path = common::safe_path::safe_join(&download_path, chunk_file)?;
         if file_path.exists() {
             return Ok(vec![]);
         }
@@ -1,11 +1,19 @@
 use anyhow::Result;
 
+pub mod builder;
 pub mod chunks;
 pub mod downloader;
 pub mod merger;
 pub mod parser;
+pub mod pkg_version;
+pub mod server;
+pub mod store;
 
+pub use builder::*;
 pub use chunks::*;
 pub use downloader::*;
 pub use merger::*;
-pub use parser::*;
\ No newline at end of file
+pub use parser::*;
+pub use pkg_version::*;
+pub use server::*;
+pub use store::*;
\ No newline at end of file
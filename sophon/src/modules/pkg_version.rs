@@ -0,0 +1,16 @@
+use common::pkg_version::PkgVersionEntry;
+
+use crate::sophon_manifest::SophonManifestProto;
+
+pub fn pkg_version_entries(proto: &SophonManifestProto) -> Vec<PkgVersionEntry> {
+    proto
+        .assets
+        .iter()
+        .filter(|asset| asset.asset_type == 0)
+        .map(|asset| PkgVersionEntry {
+            remote_name: asset.asset_name.replace("\\", "/"),
+            md5: asset.asset_hash_md5.clone(),
+            file_size: asset.asset_size as u64,
+        })
+        .collect()
+}
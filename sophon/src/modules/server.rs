@@ -0,0 +1,208 @@
+use std::{path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+
+use prost::Message;
+use reqwest::Client;
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+
+use crate::{sophon_manifest::SophonManifestProto, sophon_patch::SophonPatchProto};
+use super::*;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// Longest request line and headers a client may send.
+const MAX_REQUEST_HEAD: u64 = 16 * 1024;
+
+/// Serves a chunk store over HTTP with the layout `SophonDownloader` expects: manifests under
+/// `/manifest/<name>` and chunks under `/chunk/<name>`, so its manifest and chunk URLs can
+/// point here. Files missing from the store are fetched from the upstream URLs, when set, and
+/// kept for the next machine once they pass [`check_upstream`].
+pub struct SophonMirror {
+    store_dir: PathBuf,
+    manifest_upstream: Option<String>,
+    chunk_upstream: Option<String>,
+    client: Client,
+}
+
+/// What a fetched file was found to be, before the mirror stores it.
+enum Checked {
+    Valid,
+    /// Nothing to check it against: served, but not stored.
+    Unverifiable,
+    Invalid(String),
+}
+
+enum Response {
+    Found(Vec<u8>),
+    NotFound,
+    BadRequest,
+    MethodNotAllowed,
+    UpstreamFailed,
+}
+
+impl SophonMirror {
+    pub fn new(store_dir: &Path) -> Self {
+        Self { store_dir: store_dir.to_path_buf(), manifest_upstream: None, chunk_upstream: None, client: Client::new() }
+    }
+
+    pub fn manifest_upstream(mut self, url: &str) -> Self {
+        self.manifest_upstream = Some(url.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn chunk_upstream(mut self, url: &str) -> Self {
+        self.chunk_upstream = Some(url.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub async fn serve(self, address: &str) -> Result<()> {
+        let listener = TcpListener::bind(address).await?;
+        println!("Serving {} on http://{}", self.store_dir.display(), listener.local_addr()?);
+        println!("Manifest URL: http://{}/{}, chunk URL: http://{}/{}", listener.local_addr()?, MANIFEST_DIR, listener.local_addr()?, CHUNK_DIR);
+
+        let mirror = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let mirror = mirror.clone();
+            tokio::spawn(async move {
+                if let Err(e) = mirror.handle_connection(stream).await {
+                    eprintln!("Connection error: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let mut stream = BufReader::new(stream).take(MAX_REQUEST_HEAD);
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await?;
+        // Headers carry nothing this server needs, but a request has to end them within the limit
+        let mut complete = false;
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header).await? == 0 {
+                break;
+            }
+            if header.trim().is_empty() {
+                complete = true;
+                break;
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        let response = match method {
+            _ if !complete => Response::BadRequest,
+            "GET" | "HEAD" => self.respond(target).await,
+            _ => Response::MethodNotAllowed,
+        };
+
+        let (status, body) = match response {
+            Response::Found(body) => ("200 OK", body),
+            Response::NotFound => ("404 Not Found", Vec::new()),
+            Response::BadRequest => ("400 Bad Request", Vec::new()),
+            Response::MethodNotAllowed => ("405 Method Not Allowed", Vec::new()),
+            Response::UpstreamFailed => ("502 Bad Gateway", Vec::new()),
+        };
+        let header = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        let stream = stream.get_mut().get_mut();
+        stream.write_all(header.as_bytes()).await?;
+        if method != "HEAD" {
+            stream.write_all(&body).await?;
+        }
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn respond(&self, target: &str) -> Response {
+        let path = target.split(['?', '#']).next().unwrap_or_default().trim_start_matches('/');
+        let Some((dir, name)) = path.split_once('/') else {
+            return Response::NotFound;
+        };
+        let upstream = match dir {
+            MANIFEST_DIR => &self.manifest_upstream,
+            CHUNK_DIR => &self.chunk_upstream,
+            _ => return Response::NotFound,
+        };
+        if name.is_empty() || name.contains(['/', '\\', '%']) || name.starts_with('.') {
+            return Response::BadRequest;
+        }
+        let Ok(file_path) = common::safe_path::safe_join(&self.store_dir.join(dir), name) else {
+            return Response::BadRequest;
+        };
+
+        if let Ok(bytes) = tokio::fs::read(&file_path).await {
+            return Response::Found(bytes);
+        }
+        let Some(upstream) = upstream else {
+            return Response::NotFound;
+        };
+
+        match self.fetch(&format!("{}/{}", upstream, name), dir, &file_path).await {
+            Ok(Some(bytes)) => Response::Found(bytes),
+            Ok(None) => Response::NotFound,
+            Err(e) => {
+                eprintln!("Failed to fetch {}/{} from upstream: {}", dir, name, e);
+                Response::UpstreamFailed
+            }
+        }
+    }
+
+    /// Downloads `url` and, if it checks out, stores it at `file_path`. Returns None when
+    /// upstream has no such file.
+    async fn fetch(&self, url: &str, dir: &str, file_path: &Path) -> Result<Option<Vec<u8>>> {
+        let response = self.client.get(url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let bytes = response.error_for_status()?.bytes().await?.to_vec();
+
+        let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        match check_upstream(dir, &name, &bytes) {
+            Checked::Valid => println!("Cached {}/{} from upstream", dir, name),
+            Checked::Unverifiable => {
+                println!("Passing {}/{} through without caching it, as its name carries no hash to check", dir, name);
+                return Ok(Some(bytes));
+            }
+            Checked::Invalid(reason) => anyhow::bail!("upstream sent a bad {}: {}", dir, reason),
+        }
+
+        // Another request, or another mirror sharing the store, can be storing the same file
+        if let Some(parent) = file_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let temp_path = file_path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        tokio::fs::write(&temp_path, &bytes).await?;
+        tokio::fs::rename(&temp_path, file_path).await?;
+        Ok(Some(bytes))
+    }
+}
+
+/// Checks a file fetched from upstream before it is stored. Manifests have to decode as one;
+/// chunks have to match the 32-hex MD5 in their name once decompressed, which official chunks
+/// carry after a shorter hash (`<hash>_<md5>`) and patch files may carry on their own.
+fn check_upstream(dir: &str, name: &str, bytes: &[u8]) -> Checked {
+    if dir == MANIFEST_DIR {
+        let Ok(manifest) = zstd::decode_all(bytes) else {
+            return Checked::Invalid("not a zstd stream".to_string());
+        };
+        // Protobuf decodes most inputs into something, so an empty manifest counts as invalid too
+        let full = SophonManifestProto::decode(manifest.as_slice()).is_ok_and(|proto| !proto.assets.is_empty());
+        let patch = SophonPatchProto::decode(manifest.as_slice()).is_ok_and(|proto| !proto.patch_assets.is_empty());
+        return if full || patch { Checked::Valid } else { Checked::Invalid("not a Sophon manifest".to_string()) };
+    }
+
+    let Some(expected) = name.split('_').find(|part| part.len() == 32 && part.chars().all(|c| c.is_ascii_hexdigit())) else {
+        return Checked::Unverifiable;
+    };
+    // Chunks are zstd streams, while ldiff patch files come as they are
+    let decompressed = zstd::decode_all(bytes).ok();
+    let actual = format!("{:x}", md5::compute(decompressed.as_deref().unwrap_or(bytes)));
+    if actual.eq_ignore_ascii_case(expected) {
+        Checked::Valid
+    } else {
+        Checked::Invalid(format!("content MD5 {} does not match its name", actual))
+    }
+}
@@ -0,0 +1,162 @@
+use std::{collections::HashSet, fs::{self, File}, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, time::SystemTime};
+
+use prost::Message;
+
+use crate::sophon_manifest::SophonManifestProto;
+use super::*;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// An on-disk store of decompressed chunks, keyed by chunk name and decompressed MD5, that
+/// downloads share: chunks already in it are not fetched again, whichever install or version
+/// first needed them. Entries live at `<md5[..2]>/<chunk name>_<md5>` and are checked against
+/// their MD5 whenever they are read.
+pub struct ChunkStore {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    hits: AtomicUsize,
+    stored: AtomicUsize,
+}
+
+pub struct StoreCleanup {
+    pub removed: usize,
+    pub freed: u64,
+    pub kept_size: u64,
+}
+
+struct StoreEntry {
+    path: PathBuf,
+    size: u64,
+    used: SystemTime,
+}
+
+impl ChunkStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self { dir: dir.to_path_buf(), max_size: None, hits: AtomicUsize::new(0), stored: AtomicUsize::new(0) })
+    }
+
+    /// Caps the store at `max_size` bytes; [`ChunkStore::evict`] drops the least recently used chunks beyond it.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn stored(&self) -> usize {
+        self.stored.load(Ordering::Relaxed)
+    }
+
+    /// Chunks without a usable MD5 have no key and are never stored.
+    fn key_path(&self, chunk_name: &str, chunk_md5: &str) -> Option<PathBuf> {
+        if chunk_md5.len() != 32 || !chunk_md5.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let chunk_md5 = chunk_md5.to_ascii_lowercase();
+        common::safe_path::safe_join(&self.dir.join(&chunk_md5[..2]), &format!("{}_{}", chunk_name, chunk_md5)).ok()
+    }
+
+    /// Returns the stored chunk, or None if it is missing or no longer matches its MD5.
+    pub fn get(&self, chunk_name: &str, chunk_md5: &str) -> Option<Vec<u8>> {
+        let path = self.key_path(chunk_name, chunk_md5)?;
+        let bytes = fs::read(&path).ok()?;
+        if !format!("{:x}", md5::compute(&bytes)).eq_ignore_ascii_case(chunk_md5) {
+            eprintln!("Dropping corrupt chunk {} from the store", chunk_name);
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        // The modification time doubles as the last use, which eviction goes by
+        let _ = File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(bytes)
+    }
+
+    /// Adds a decompressed chunk, refusing bytes that do not match `chunk_md5`.
+    pub fn put(&self, chunk_name: &str, chunk_md5: &str, bytes: &[u8]) -> Result<()> {
+        let Some(path) = self.key_path(chunk_name, chunk_md5) else {
+            return Ok(());
+        };
+        if !format!("{:x}", md5::compute(bytes)).eq_ignore_ascii_case(chunk_md5) {
+            anyhow::bail!("Chunk {} does not match its MD5 {}", chunk_name, chunk_md5);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Another asset, or another download sharing the store, can be storing the same chunk at once
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&temp_path, bytes)?;
+        fs::rename(&temp_path, &path)?;
+        self.stored.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Removes the least recently used chunks until the store fits its size cap, if it has one.
+    pub fn evict(&self) -> Result<StoreCleanup> {
+        let mut entries = self.entries()?;
+        let mut kept_size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut cleanup = StoreCleanup { removed: 0, freed: 0, kept_size };
+        let Some(max_size) = self.max_size else {
+            return Ok(cleanup);
+        };
+
+        entries.sort_by_key(|entry| entry.used);
+        for entry in entries {
+            if kept_size <= max_size {
+                break;
+            }
+            fs::remove_file(&entry.path)?;
+            kept_size -= entry.size;
+            cleanup.removed += 1;
+            cleanup.freed += entry.size;
+        }
+        cleanup.kept_size = kept_size;
+        Ok(cleanup)
+    }
+
+    /// Removes every chunk that none of `manifests` references.
+    pub fn gc(&self, manifests: &[SophonManifestProto]) -> Result<StoreCleanup> {
+        let referenced: HashSet<PathBuf> = manifests
+            .iter()
+            .flat_map(|manifest| &manifest.assets)
+            .flat_map(|asset| &asset.asset_chunks)
+            .filter_map(|chunk| self.key_path(&chunk.chunk_name, &chunk.chunk_decompressed_hash_md5))
+            .collect();
+
+        let mut cleanup = StoreCleanup { removed: 0, freed: 0, kept_size: 0 };
+        for entry in self.entries()? {
+            if referenced.contains(&entry.path) {
+                cleanup.kept_size += entry.size;
+            } else {
+                fs::remove_file(&entry.path)?;
+                cleanup.removed += 1;
+                cleanup.freed += entry.size;
+            }
+        }
+        Ok(cleanup)
+    }
+
+    fn entries(&self) -> Result<Vec<StoreEntry>> {
+        let mut entries = Vec::new();
+        for entry in walkdir::WalkDir::new(&self.dir).min_depth(2).max_depth(2) {
+            let entry = entry?;
+            // Temporary files belong to writes still in progress
+            if !entry.file_type().is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            entries.push(StoreEntry { path: entry.into_path(), size: metadata.len(), used: metadata.modified()? });
+        }
+        Ok(entries)
+    }
+}
+
+/// Reads a full manifest from disk, either as served (zstd) or already decompressed.
+pub fn read_manifest(path: &Path) -> Result<SophonManifestProto> {
+    let bytes = fs::read(path)?;
+    let bytes = zstd::decode_all(bytes.as_slice()).unwrap_or(bytes);
+    SophonManifestProto::decode(bytes.as_slice()).map_err(|e| anyhow::anyhow!("{} is not a full manifest: {}", path.display(), e))
+}
@@ -1,6 +1,6 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
-use crate::modules::{Manifest, SophonChunks, SophonDownloader, SophonMerger, SophonParser};
+use crate::modules::{pkg_version_entries, ChunkStore, Manifest, SophonChunks, SophonDownloader, SophonMerger, SophonParser};
 use anyhow::Result;
 
 pub struct SophonClient {
@@ -9,7 +9,8 @@ pub struct SophonClient {
     parser: SophonParser,
     manifest_url: String,
     manifest_file: String,
-    chunk_url: String
+    chunk_url: String,
+    chunk_store: Option<Arc<ChunkStore>>,
 }
 
 impl SophonClient {
@@ -24,18 +25,37 @@ impl SophonClient {
             parser: SophonParser::new(),
             manifest_url: manifest_url.to_string(),
             manifest_file: manifest_file.to_string(),
-            chunk_url: chunk_url.to_string()
+            chunk_url: chunk_url.to_string(),
+            chunk_store: None,
         }
     }
+
+    /// Reuses chunks from `store` and adds the ones this download fetches to it.
+    pub fn chunk_store(mut self, store: ChunkStore) -> Self {
+        self.chunk_store = Some(Arc::new(store));
+        self
+    }
     
     pub async fn download_game(&self, output_dir: &str) -> Result<()> {
         let manifest = self.downloader.download_and_extract_manifest(&self.manifest_url, &self.manifest_file, &output_dir, false).await?;
         let manifest_proto = self.parser.parse_manifest_file(manifest)?;
-        let chunks = SophonChunks::new(self.downloader.clone(), self.merger, &self.chunk_url);
+        let chunks = SophonChunks::new(self.downloader.clone(), self.merger, &self.chunk_url).store(self.chunk_store.clone());
         match manifest_proto {
             Manifest::Full(proto) => {
                 let _ = std::fs::remove_file(Path::new(output_dir).join(format!("{}~", self.manifest_file)));
-                chunks.parse_manifest_proto(proto, output_dir).await?
+                let entries = pkg_version_entries(&proto);
+                chunks.parse_manifest_proto(proto, output_dir).await?;
+                common::pkg_version::write_pkg_version(&Path::new(output_dir).join(common::pkg_version::PKG_VERSION_FILE), &entries)?;
+                if let Some(store) = &self.chunk_store {
+                    let cleanup = store.evict()?;
+                    println!(
+                        "Chunk store: {} chunks reused, {} added, {} evicted ({:.1} MiB kept)",
+                        store.hits(),
+                        store.stored(),
+                        cleanup.removed,
+                        cleanup.kept_size as f64 / 1024.0 / 1024.0
+                    );
+                }
             },
             Manifest::Diff(proto) => chunks.parse_manifest_diff_proto(proto, output_dir).await?
         }